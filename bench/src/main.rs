@@ -122,7 +122,7 @@ fn main() -> ! {
         for t in 1..=2 {
             let mut result = 0;
             let duration = run_test(&timer, || {
-                result = module.call("prime", &[0, size]).unwrap();
+                result = module.as_mut().call("prime", &[0, size]).unwrap();
             });
 
             write!(
@@ -139,21 +139,19 @@ fn main() -> ! {
     //     let mut module = WasmModule::from_wasm(wasm).unwrap();
 
     //     for t in 1..=2 {
-    //         for i in 0..=size {
-    //             module.memory.write_memory(i, i as u8);
-    //         }
+    //         let input: Vec<u8> = (0..=size).map(|i| i as u8).collect();
+    //         let memory = module.as_mut().memory_mut().memory_mut(0).unwrap();
+    //         memory.write(0, &input).unwrap();
 
     //         let mut ptr = 0i32;
     //         let duration = run_test(&timer, || {
-    //             ptr = module.call("hash", &[0, size]).unwrap();
+    //             ptr = module.as_mut().call("hash", &[0, size]).unwrap();
     //         });
 
+    //         let memory = module.memory.memory(0).unwrap();
     //         let mut hash = String::new();
-    //         for i in 0..16 {
-    //             hash.push_str(&format!(
-    //                 "{:02x}",
-    //                 module.memory.read_memory(ptr as u32 + i)
-    //             ));
+    //         for byte in memory.slice(ptr as u32, 16).unwrap() {
+    //             hash.push_str(&format!("{:02x}", byte));
     //         }
 
     //         write!(
@@ -175,7 +173,7 @@ fn main() -> ! {
     //     for t in 1..=2 {
     //         let mut checksum = 0;
     //         let duration = run_test(&timer, || {
-    //             checksum = module.call("mandelbrot", &[size]).unwrap();
+    //             checksum = module.as_mut().call("mandelbrot", &[size]).unwrap();
     //         });
 
     //         write!(
@@ -194,11 +192,12 @@ fn main() -> ! {
     //     for t in 1..=2 {
     //         let mut out_ptr = 0;
     //         let duration = run_test(&timer, || {
-    //             out_ptr = module.call("nbody", &[size]).unwrap();
+    //             out_ptr = module.as_mut().call("nbody", &[size]).unwrap();
     //         });
 
-    //         let energy_start = f32::from_bits(module.memory.read_memory32(out_ptr as u32));
-    //         let energy_end = f32::from_bits(module.memory.read_memory32((out_ptr + 4) as u32));
+    //         let memory = module.memory.memory(0).unwrap();
+    //         let energy_start = memory.get::<f32>(out_ptr as u32).unwrap();
+    //         let energy_end = memory.get::<f32>(out_ptr as u32 + 4).unwrap();
 
     //         write!(
     //             uart,
@@ -218,19 +217,14 @@ fn main() -> ! {
     //     for t in 1..=2 {
     //         let mut out_ptr = 0;
     //         let duration = run_test(&timer, || {
-    //             out_ptr = module.call("sort", &[size]).unwrap();
+    //             out_ptr = module.as_mut().call("sort", &[size]).unwrap();
     //         });
 
+    //         let memory = module.memory.memory(0).unwrap();
     //         let mut sorted = String::new();
-
     //         for i in 0..size {
-    //             sorted.push_str(
-    //                 format!(
-    //                     "{} ",
-    //                     module.memory.read_memory32((i << 2) + out_ptr as u32)
-    //                 )
-    //                 .as_str(),
-    //             );
+    //             let value = memory.get::<u32>(out_ptr as u32 + (i << 2)).unwrap();
+    //             sorted.push_str(format!("{} ", value).as_str());
     //         }
 
     //         write!(
@@ -22,13 +22,14 @@ impl<'a> FromReader<'a> for MemoryType {
     fn from_reader(reader: &mut BinaryReader<'a>) -> Result<Self> {
         let pos = reader.original_position();
         let flags = reader.read_u8()?;
-        if (flags & !0b111) != 0 {
+        if (flags & !0b1111) != 0 {
             bail!(pos, "invalid memory limits flags");
         }
 
         let memory64 = flags & 0b100 != 0;
         let shared = flags & 0b010 != 0;
         let has_max = flags & 0b001 != 0;
+        let has_page_size = flags & 0b1000 != 0;
         Ok(MemoryType {
             memory64,
             shared,
@@ -51,6 +52,11 @@ impl<'a> FromReader<'a> for MemoryType {
             } else {
                 Some(reader.read_var_u32()?.into())
             },
+            page_size_log2: if has_page_size {
+                Some(reader.read_var_u32()?)
+            } else {
+                None
+            },
         })
     }
 }
@@ -179,6 +179,12 @@ pub struct MemoryType {
     /// be at most `u32::MAX` for valid types. This field is always present for
     /// valid wasm memories when `shared` is `true`.
     pub maximum: Option<u64>,
+
+    /// The log base 2 of the memory's page size, if it isn't the default of
+    /// 64 KiB.
+    ///
+    /// This is part of the custom-page-sizes proposal in WebAssembly.
+    pub page_size_log2: Option<u32>,
 }
 
 impl MemoryType {
@@ -707,6 +707,10 @@ impl Module {
         offset: usize,
     ) -> Result<()> {
         self.check_limits(ty.initial, ty.maximum, offset)?;
+        let page_size_log2 = ty.page_size_log2.unwrap_or(16);
+        if page_size_log2 != 0 && page_size_log2 != 16 {
+            return Err(BinaryReaderError::new("invalid custom page size", offset));
+        }
         let (true_maximum, err) = if ty.memory64 {
             if !features.memory64 {
                 return Err(BinaryReaderError::new(
@@ -720,8 +724,9 @@ impl Module {
             )
         } else {
             (
-                MAX_WASM_MEMORY32_PAGES,
-                "memory size must be at most 65536 pages (4GiB)",
+                // Smaller pages still have to fit in 4GiB
+                MAX_WASM_MEMORY32_PAGES << (16 - page_size_log2),
+                "memory size must be at most 4GiB",
             )
         };
         if ty.initial > true_maximum {
@@ -0,0 +1,57 @@
+//! Ahead-of-time compiler for pico-jit
+//!
+//! Compiles every function in a WASM module on the host and writes a code image that can be
+//! shipped with the firmware and loaded with `WasmModule::from_wasm_with_image`.
+
+use pico_jit::compiler::CompilerOptions;
+use pico_jit::image::CodeImage;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    let mut options = CompilerOptions::default();
+    let mut args: Vec<String> = std::env::args().collect();
+    args.retain(|arg| match arg.as_str() {
+        "--canonicalize-nans" => {
+            options.canonicalize_nans = true;
+            false
+        }
+        _ => true,
+    });
+
+    let [_, input, output] = args.as_slice() else {
+        eprintln!("Usage: pico-aot [--canonicalize-nans] <input.wasm> <output.pjit>");
+        return ExitCode::FAILURE;
+    };
+
+    let wasm = match std::fs::read(input) {
+        Ok(wasm) => wasm,
+        Err(e) => {
+            eprintln!("Could not read {}: {}", input, e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let image = match CodeImage::compile(&wasm, options) {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("Could not compile {}: {}", input, e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if let Err(e) = std::fs::write(output, image.to_bytes()) {
+        eprintln!("Could not write {}: {}", output, e);
+        return ExitCode::FAILURE;
+    }
+
+    let code_size: usize = image.functions.iter().map(|f| f.code.len() * 2).sum();
+    println!(
+        "Compiled {} functions ({} bytes of code, {} exports) into {}",
+        image.functions.len(),
+        code_size,
+        image.exports.len(),
+        output
+    );
+
+    ExitCode::SUCCESS
+}
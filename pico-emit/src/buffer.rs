@@ -5,6 +5,7 @@ pub struct JitBuffer {
     buffer: Vec<u16>, // Emitted code buffer
     data: Vec<u16>, // Used for data (not code) that the function will use. Copied into the code buffer when the function is built
     pub current_code_section_size: usize,
+    out_of_memory: bool, // Set once an allocation fails, after which the contents are meaningless
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -49,29 +50,56 @@ impl JitBuffer {
             buffer: Vec::with_capacity(1),
             data: Vec::new(),
             current_code_section_size: 0,
+            out_of_memory: false,
         }
     }
 
+    pub(crate) fn is_out_of_memory(&self) -> bool {
+        self.out_of_memory
+    }
+
+    /// Records an allocation that failed outside the buffer, the build will fail the same way
+    pub(crate) fn set_out_of_memory(&mut self) {
+        self.out_of_memory = true;
+    }
+
+    // Reserves space in a buffer without aborting if the allocation fails
+    fn try_extend(out_of_memory: &mut bool, buffer: &mut Vec<u16>, values: &[u16]) {
+        if *out_of_memory || buffer.try_reserve(values.len()).is_err() {
+            *out_of_memory = true;
+            return;
+        }
+
+        buffer.extend_from_slice(values);
+    }
+
     pub(crate) fn push(&mut self, instruction: u16) {
-        self.buffer.push(instruction);
+        Self::try_extend(&mut self.out_of_memory, &mut self.buffer, &[instruction]);
         self.current_code_section_size += 1;
     }
 
     pub(crate) fn push_empty(&mut self) -> Offset {
         let offset = self.buffer.len();
-        self.buffer.push(0);
+        Self::try_extend(&mut self.out_of_memory, &mut self.buffer, &[0]);
         Offset::Instruction(offset)
     }
 
     pub(crate) fn push_data(&mut self, data: u32) -> Offset {
         let offset = self.data.len();
-        self.data.push((data & 0xFFFF) as u16);
-        self.data.push((data >> 16) as u16);
+        Self::try_extend(
+            &mut self.out_of_memory,
+            &mut self.data,
+            &[(data & 0xFFFF) as u16, (data >> 16) as u16],
+        );
 
         Offset::Data(offset)
     }
 
     pub(crate) fn fill_instruction(&mut self, offset: Offset, instruction: u16) {
+        if self.out_of_memory {
+            return;
+        }
+
         match offset {
             Offset::Instruction(offset) => {
                 self.buffer[offset] = instruction;
@@ -89,7 +117,9 @@ impl JitBuffer {
     }
 
     pub(crate) fn current_offset(&self) -> Offset {
-        Offset::Instruction(self.buffer.len() - 2)
+        // The buffer can be shorter than expected after a failed allocation, but then the offset
+        // is never used
+        Offset::Instruction(self.buffer.len().saturating_sub(2))
     }
 
     // Helper function to get the absolute memory offset of a label
@@ -114,21 +144,28 @@ impl JitBuffer {
     // Does not clear the data section
     pub(crate) fn write_data_section(&mut self) {
         if self.buffer.len() % 2 == 1 {
-            self.buffer.push(0); // Pad with a 0 if the function end is not word aligned
+            // Pad with a 0 if the function end is not word aligned
+            Self::try_extend(&mut self.out_of_memory, &mut self.buffer, &[0]);
         }
 
-        self.buffer.extend_from_slice(&self.data); // Append the data to the end of the code buffer
+        // Append the data to the end of the code buffer
+        Self::try_extend(&mut self.out_of_memory, &mut self.buffer, &self.data);
         self.current_code_section_size = 0; // Reset the code section size
     }
 
-    pub(crate) fn finish(mut self) -> Box<[u16]> {
+    /// Returns the finished code, or `None` if an allocation failed while building it
+    pub(crate) fn finish(mut self) -> Option<Box<[u16]>> {
         self.write_data_section();
+        if self.out_of_memory {
+            return None;
+        }
+
         self.buffer.shrink_to_fit(); // Shrink the buffer to the minimum size needed
         let buffer_ptr = self.buffer.as_ptr();
         let slice = self.buffer.into_boxed_slice();
         let slice_ptr = slice.as_ptr();
         assert_eq!(slice_ptr, buffer_ptr); // Ensure the buffer is not moved
-        slice
+        Some(slice)
     }
 
     pub fn copy_to_slice(&self, slice: &mut [u16]) {
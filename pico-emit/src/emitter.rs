@@ -1,10 +1,13 @@
 use crate::buffer::{JitBuffer, Offset};
 use crate::instructions::{Condition, LabelInstruction, ToInstEncoding};
 use crate::JitFn;
-use alloc::vec;
 use alloc::vec::Vec;
 use ux2::i11;
 
+/// Returned by `Emitter::try_build` when memory for the function could not be allocated
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct OutOfMemory;
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Label {
     Unresolved(usize),
@@ -80,7 +83,22 @@ impl Emitter {
             }
         }
 
-        let mut new_unfilled_instructions = vec![];
+        if self.buffer.is_out_of_memory() {
+            // Offsets are meaningless once the buffer is incomplete, the build will fail anyway
+            self.unfilled_instructions.clear();
+            return;
+        }
+
+        // Unresolved instructions are kept, so this never needs more than the current capacity
+        let mut new_unfilled_instructions = Vec::new();
+        if new_unfilled_instructions
+            .try_reserve(self.unfilled_instructions.len())
+            .is_err()
+        {
+            self.buffer.set_out_of_memory();
+            self.unfilled_instructions.clear();
+            return;
+        }
 
         for (position, instruction) in self.unfilled_instructions.iter() {
             match instruction {
@@ -244,6 +262,17 @@ impl Emitter {
         self.unfilled_instructions = new_unfilled_instructions;
     }
 
+    // Remembers an instruction to fill in once its label is resolved, without aborting if there
+    // is no memory for it
+    pub(crate) fn push_unfilled(&mut self, offset: Offset, instruction: LabelInstruction) {
+        if self.unfilled_instructions.try_reserve(1).is_err() {
+            self.buffer.set_out_of_memory();
+            return;
+        }
+
+        self.unfilled_instructions.push((offset, instruction));
+    }
+
     pub fn create_label(&mut self) -> Label {
         let label = Label::Unresolved(self.label_counter);
         self.label_counter += 1;
@@ -263,13 +292,20 @@ impl Emitter {
     }
 
     /// Builds the function and returns it
-    pub fn build(mut self) -> JitFn {
+    pub fn build(self) -> JitFn {
+        self.try_build()
+            .expect("Out of memory while building function")
+    }
+
+    /// Builds the function, returning an error instead of aborting if an allocation failed at
+    /// any point while emitting it
+    pub fn try_build(mut self) -> Result<JitFn, OutOfMemory> {
         // Fill in the label instructions
         self.fill_label_instructions(false);
 
         // Finish the buffer
-        JitFn {
-            data: self.buffer.finish(),
-        }
+        Ok(JitFn {
+            data: self.buffer.finish().ok_or(OutOfMemory)?,
+        })
     }
 }
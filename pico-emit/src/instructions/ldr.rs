@@ -22,8 +22,7 @@ impl Ldr<LowRegister, StackPointer> for Emitter {
 impl Ldr<LowRegister, Label> for Emitter {
     fn ldr(&mut self, dest: LowRegister, label: Label) {
         let offset = self.buffer.push_empty();
-        self.unfilled_instructions
-            .push((offset, LabelInstruction::LDR(label, dest)));
+        self.push_unfilled(offset, LabelInstruction::LDR(label, dest));
     }
 }
 
@@ -119,8 +119,7 @@ pub trait Adr {
 impl Adr for Emitter {
     fn adr(&mut self, dest: LowRegister, label: Label) {
         let offset = self.buffer.push_empty();
-        self.unfilled_instructions
-            .push((offset, LabelInstruction::ADR(label, dest)));
+        self.push_unfilled(offset, LabelInstruction::ADR(label, dest));
     }
 }
 
@@ -136,8 +135,7 @@ impl B for Emitter {
 
     fn b_if(&mut self, condition: Condition, label: Label) {
         let offset = self.buffer.push_empty();
-        self.unfilled_instructions
-            .push((offset, LabelInstruction::B(label, condition)));
+        self.push_unfilled(offset, LabelInstruction::B(label, condition));
     }
 }
 
@@ -150,15 +148,13 @@ impl Branch for Emitter {
     fn branch(&mut self, label: Label) {
         let offset = self.buffer.push_empty();
         self.nop(); // Reserve space in case branch becomes a BL
-        self.unfilled_instructions
-            .push((offset, LabelInstruction::Branch(label, Condition::AL)));
+        self.push_unfilled(offset, LabelInstruction::Branch(label, Condition::AL));
     }
 
     fn branch_if(&mut self, condition: Condition, label: Label) {
         let offset = self.buffer.push_empty();
         self.nop(); // Reserve space in case branch becomes a BL
         self.nop();
-        self.unfilled_instructions
-            .push((offset, LabelInstruction::Branch(label, condition)));
+        self.push_unfilled(offset, LabelInstruction::Branch(label, condition));
     }
 }
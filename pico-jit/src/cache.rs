@@ -0,0 +1,181 @@
+//! Persistent storage for compiled functions
+//!
+//! `WasmModule` consults its `CodeCache` before compiling a function and stores the result
+//! afterwards, so modules that are loaded again (e.g. after every reboot) only pay for compilation
+//! once. Compiled code is position independent, so it can be reused by any instance of the module.
+use crate::compiler::COMPILER_VERSION;
+use crate::image::{ImageReader, ImageWriter};
+use crate::wasm_module::{Result, WasmError};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+
+const MAGIC: &[u8; 4] = b"PJCC";
+
+/// Version of the layout written by `InMemoryCodeCache::to_bytes`
+pub const CACHE_FORMAT_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CacheKey {
+    /// Hash of the module bytes, see `image::module_hash`
+    pub module_hash: u64,
+    pub function_index: u32,
+    pub compiler_version: u32,
+    /// `CompilerOptions::to_bits` of the options the code was compiled with
+    pub compiler_options: u32,
+}
+
+/// Storage for compiled functions
+///
+/// Implementations must only return code stored under exactly the same key, and should drop any
+/// entry for the same function that was stored under a different module hash, compiler version or
+/// compiler options.
+pub trait CodeCache {
+    fn get(&mut self, key: &CacheKey) -> Option<Box<[u16]>>;
+    /// Stores compiled code, which may be skipped, e.g. when there is no memory for a copy
+    fn insert(&mut self, key: CacheKey, code: &[u16]);
+}
+
+impl<T: CodeCache + ?Sized> CodeCache for &mut T {
+    fn get(&mut self, key: &CacheKey) -> Option<Box<[u16]>> {
+        (**self).get(key)
+    }
+
+    fn insert(&mut self, key: CacheKey, code: &[u16]) {
+        (**self).insert(key, code)
+    }
+}
+
+// (module hash, function index) -> (compiler version, compiler options, code)
+type CacheEntries = BTreeMap<(u64, u32), (u32, u32, Box<[u16]>)>;
+
+/// A `CodeCache` kept on the heap, which can be saved to and restored from a byte blob
+///
+/// Entries are stored per module hash and function index, with the compiler version and options
+/// checked on every lookup.
+#[derive(Debug, Default)]
+pub struct InMemoryCodeCache {
+    entries: CacheEntries,
+}
+
+impl InMemoryCodeCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Drops every entry belonging to a module, e.g. when it has been replaced
+    pub fn remove_module(&mut self, module_hash: u64) {
+        self.entries.retain(|(hash, _), _| *hash != module_hash);
+    }
+
+    /// Serializes the cache, e.g. to write it to flash or a file
+    ///
+    /// The layout is little-endian: magic `b"PJCC"`, format version, entry count, then per entry
+    /// the module hash (u64), function index, compiler version, compiler options, code length in
+    /// halfwords and the code itself.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = ImageWriter::default();
+        writer.bytes(MAGIC);
+        writer.u32(CACHE_FORMAT_VERSION);
+        writer.u32(self.entries.len() as u32);
+
+        for ((module_hash, function_index), (compiler_version, compiler_options, code)) in
+            &self.entries
+        {
+            writer.u64(*module_hash);
+            writer.u32(*function_index);
+            writer.u32(*compiler_version);
+            writer.u32(*compiler_options);
+            writer.u32(code.len() as u32);
+            for halfword in code.iter() {
+                writer.u16(*halfword);
+            }
+        }
+
+        writer.finish()
+    }
+
+    /// Restores a cache written by `to_bytes`
+    ///
+    /// Entries from a different compiler version are dropped, since they can never be used.
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let mut reader = ImageReader::new(data);
+        if reader.bytes(MAGIC.len())? != MAGIC {
+            return Err(WasmError::InvalidImage("bad cache magic".to_string()));
+        }
+
+        if reader.u32()? != CACHE_FORMAT_VERSION {
+            return Err(WasmError::InvalidImage(
+                "cache format version is not supported".to_string(),
+            ));
+        }
+
+        let mut cache = Self::new();
+        let entry_count = reader.u32()?;
+        for _ in 0..entry_count {
+            let module_hash = reader.u64()?;
+            let function_index = reader.u32()?;
+            let compiler_version = reader.u32()?;
+            let compiler_options = reader.u32()?;
+            let code_len = reader.u32()?;
+            let mut code = Vec::new();
+            for _ in 0..code_len {
+                code.push(reader.u16()?);
+            }
+
+            if compiler_version == COMPILER_VERSION {
+                cache.entries.insert(
+                    (module_hash, function_index),
+                    (compiler_version, compiler_options, code.into_boxed_slice()),
+                );
+            }
+        }
+
+        Ok(cache)
+    }
+}
+
+impl CodeCache for InMemoryCodeCache {
+    fn get(&mut self, key: &CacheKey) -> Option<Box<[u16]>> {
+        let entry_key = (key.module_hash, key.function_index);
+        let (compiler_version, compiler_options, code) = self.entries.get(&entry_key)?;
+        if *compiler_version != key.compiler_version || *compiler_options != key.compiler_options {
+            self.entries.remove(&entry_key);
+            return None;
+        }
+
+        // A copy that doesn't fit on the heap is a miss, so the function is compiled instead
+        let mut copy = Vec::new();
+        copy.try_reserve_exact(code.len()).ok()?;
+        copy.extend_from_slice(code);
+        Some(copy.into_boxed_slice())
+    }
+
+    fn insert(&mut self, key: CacheKey, code: &[u16]) {
+        // Caching is best effort, so code that doesn't fit on the heap just isn't kept. The map
+        // node itself can't be allocated fallibly, see `WasmError::OutOfMemory`.
+        let mut copy = Vec::new();
+        if copy.try_reserve_exact(code.len()).is_err() {
+            return;
+        }
+        copy.extend_from_slice(code);
+
+        self.entries.insert(
+            (key.module_hash, key.function_index),
+            (
+                key.compiler_version,
+                key.compiler_options,
+                copy.into_boxed_slice(),
+            ),
+        );
+    }
+}
@@ -1,51 +1,112 @@
+use crate::context::ContextField;
 use crate::generation::{
-    bulk_memory::*, control_flow::*, conversion::*, f32_ops::*, get_data_label, globals::*,
-    i32_ops::*, locals::*, memory::*,
+    atomics::*, bulk_memory::*, control_flow::*, conversion::*, exceptions::*, f32_ops::*,
+    get_data_label, globals::*, i32_ops::*, load_context_field, load_index, locals::*, memory::*,
+    raise_trap, tables::*,
 };
-use crate::wasm_module::{Result, WasmError, WasmModule};
-use alloc::boxed::Box;
+use crate::wasm_module::{Result, Trap, WasmError};
 use alloc::collections::BTreeMap;
 use alloc::format;
-use alloc::vec;
+use alloc::string::ToString;
 use alloc::vec::Vec;
-use core::iter::repeat;
+use core::iter::{repeat, repeat_n};
 use pico_emit::emitter::Label;
 use pico_emit::instructions::*;
 use pico_emit::registers::*;
 use pico_emit::{register_list, Emitter, JitFn};
-use ux2::{u5, u7};
-use wasmparser_nostd::{FuncType, FunctionBody, Operator, ValType, WasmFuncType};
+use ux2::u7;
+use wasmparser_nostd::{
+    BlockType, FuncToValidate, FuncType, FunctionBody, GlobalType, MemoryType, Operator, TableType,
+    ValType, WasmFeatures, WasmFuncType, WasmModuleResources,
+};
 
 use crate::aliases::*;
 
+/// Identifies the code generator, bump this whenever the generated code for an existing function
+/// changes so that stored code from an older compiler is never run
+pub const COMPILER_VERSION: u32 = 27;
+
+/// Code generation settings, which are part of every cache key since they change the output
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CompilerOptions {
+    /// Replace NaNs produced by float arithmetic with the canonical NaN, so that results are the
+    /// same across runs and engines
+    pub canonicalize_nans: bool,
+}
+
+impl CompilerOptions {
+    pub fn to_bits(self) -> u32 {
+        self.canonicalize_nans as u32
+    }
+
+    pub fn from_bits(bits: u32) -> Self {
+        CompilerOptions {
+            canonicalize_nans: bits & 1 != 0,
+        }
+    }
+}
+
 pub(crate) enum Scope {
     Block(Label),
     Loop(Label),
-    If { else_label: Label, end_label: Label },
+    If {
+        else_label: Label,
+        end_label: Label,
+    },
+    /// `slot` is the first of the block's two hidden locals, `next_clause` is where the last catch
+    /// clause goes when the exception doesn't match
+    Try {
+        end_label: Label,
+        handler: Label,
+        slot: u32,
+        catching: bool,
+        next_clause: Option<Label>,
+    },
 }
 
-#[derive(Debug)]
-pub(crate) struct WasmContext<'a> {
-    pub(crate) module_ptr: *const WasmModule<'a>,
-    pub(crate) call_func: fn(&mut WasmModule<'a>, u32, *const u32) -> *const u32,
-    pub(crate) memory_ptr: *mut u8,
-    pub(crate) global_ptr: *mut u32,
-    pub(crate) memory_size: u32,
+/// Upper bound on params and locals of a function, the same limit other engines use
+///
+/// Whether a frame actually fits on the WASM stack is checked when the function is entered.
+const MAX_LOCALS: u32 = 50_000;
+
+/// Words the helpers that run on the WASM stack may use below a function's operands
+///
+/// Only leaf helpers do, e.g. soft float, division and the unaligned accesses, whose frames are a
+/// few words. Helpers that allocate or call into the host switch to the native stack first, see
+/// `call_native`.
+const HELPER_STACK: u32 = 64;
+
+// Makes room for `additional` more elements, failing with `OutOfMemory` rather than aborting
+fn try_reserve<T>(vec: &mut Vec<T>, additional: usize) -> Result<()> {
+    vec.try_reserve(additional)
+        .map_err(|_| WasmError::OutOfMemory)
 }
 
-fn read_local_types(ty: &FuncType, body: &FunctionBody) -> Result<Box<[ValType]>> {
-    let mut params = ty.params().to_vec();
+fn read_local_types(ty: &FuncType, body: &FunctionBody) -> Result<Vec<ValType>> {
+    let mut params = Vec::new();
+    try_reserve(&mut params, ty.params().len())?;
+    params.extend_from_slice(ty.params());
     let local_reader = body.get_locals_reader()?;
     for local in local_reader {
         let (count, ty) = local?;
-        if ty != ValType::I32 && ty != ValType::F32 {
-            unimplemented!("Unsupported local type: {:?}", ty)
+        // References are one word each, like i32 and f32
+        if !matches!(
+            ty,
+            ValType::I32 | ValType::F32 | ValType::FuncRef | ValType::ExternRef
+        ) {
+            return Err(WasmError::UnsupportedType(ty));
+        }
+
+        let total = params.len() as u64 + count as u64;
+        if total > MAX_LOCALS as u64 {
+            return Err(WasmError::TooManyLocals(total.min(u32::MAX as u64) as u32));
         }
 
+        try_reserve(&mut params, count as usize)?;
         params.extend(repeat(ty).take(count as usize));
     }
 
-    Ok(params.into_boxed_slice())
+    Ok(params)
 }
 
 // pub(crate) fn check_stack_underflow(stack: *const u32, locals: *const u32) {
@@ -57,54 +118,242 @@ fn read_local_types(ty: &FuncType, body: &FunctionBody) -> Result<Box<[ValType]>
 //     }
 // }
 
-fn wasm_panic(code: u32) {
-    match code {
-        0 => panic!("Unreachable executed"),
-        1 => panic!("memory.grow called with non-zero size (grow is not supported)"),
-        _ => panic!("Unknown panic code: {}", code),
+/// Proposals the compiler supports, function bodies using anything else fail validation
+const FEATURES: WasmFeatures = WasmFeatures {
+    mutable_global: true,
+    saturating_float_to_int: true,
+    sign_extension: true,
+    reference_types: true,
+    multi_value: true,
+    bulk_memory: true,
+    simd: false,
+    relaxed_simd: false,
+    threads: true,
+    tail_call: true,
+    floats: true,
+    multi_memory: true,
+    exceptions: true,
+    memory64: false,
+    extended_const: false,
+    component_model: false,
+    memory_control: false,
+};
+
+/// What the compiler needs to know about the rest of the module
+pub(crate) struct ModuleTypes<'a> {
+    pub(crate) globals: &'a [GlobalType],
+    pub(crate) types: &'a [FuncType],
+    /// Type index of every function, imported ones first
+    pub(crate) functions: &'a [u32],
+    /// Type index of every tag
+    pub(crate) tags: &'a [u32],
+    pub(crate) tables: &'a [TableType],
+    /// Imported memories first
+    pub(crate) memories: &'a [MemoryType],
+    /// Type of every element segment
+    pub(crate) elements: &'a [ValType],
+    /// From the data count section, which bodies using `memory.init` or `data.drop` need
+    pub(crate) data_count: Option<u32>,
+}
+
+// Lets the validator check function bodies against the module
+impl WasmModuleResources for ModuleTypes<'_> {
+    type FuncType = FuncType;
+
+    fn table_at(&self, at: u32) -> Option<TableType> {
+        self.tables.get(at as usize).copied()
+    }
+
+    fn memory_at(&self, at: u32) -> Option<MemoryType> {
+        self.memories.get(at as usize).copied()
+    }
+
+    fn tag_at(&self, at: u32) -> Option<&FuncType> {
+        self.func_type_at(*self.tags.get(at as usize)?)
+    }
+
+    fn global_at(&self, at: u32) -> Option<GlobalType> {
+        self.globals.get(at as usize).copied()
+    }
+
+    fn func_type_at(&self, type_idx: u32) -> Option<&FuncType> {
+        self.types.get(type_idx as usize)
+    }
+
+    fn type_of_function(&self, func_idx: u32) -> Option<&FuncType> {
+        self.func_type_at(*self.functions.get(func_idx as usize)?)
+    }
+
+    fn element_type_at(&self, at: u32) -> Option<ValType> {
+        self.elements.get(at as usize).copied()
+    }
+
+    fn element_count(&self) -> u32 {
+        self.elements.len() as u32
+    }
+
+    fn data_count(&self) -> Option<u32> {
+        self.data_count
+    }
+
+    // Which functions are declared for `ref.func` isn't tracked, so every reference is allowed
+    fn is_function_referenced(&self, _idx: u32) -> bool {
+        true
     }
 }
 
+impl ModuleTypes<'_> {
+    fn function_type(&self, function_index: u32) -> Result<&FuncType> {
+        self.functions
+            .get(function_index as usize)
+            .and_then(|index| self.types.get(*index as usize))
+            .ok_or_else(|| WasmError::FunctionNotFound(function_index.to_string()))
+    }
+
+    fn type_at(&self, type_index: u32) -> Result<&FuncType> {
+        self.types
+            .get(type_index as usize)
+            .ok_or_else(|| WasmError::UnsupportedOp(format!("Type {} does not exist", type_index)))
+    }
+
+    // Number of values an exception with this tag carries
+    fn tag_payload(&self, tag_index: u32) -> Result<usize> {
+        let type_index = self
+            .tags
+            .get(tag_index as usize)
+            .ok_or_else(|| WasmError::UnsupportedOp(format!("Tag {} does not exist", tag_index)))?;
+        Ok(self.type_at(*type_index)?.len_inputs())
+    }
+
+    fn block_params(&self, ty: BlockType) -> Result<usize> {
+        match ty {
+            BlockType::FuncType(index) => Ok(self.type_at(index)?.len_inputs()),
+            BlockType::Empty | BlockType::Type(_) => Ok(0),
+        }
+    }
+}
+
+/// Compiles a function body into Thumb code
+///
+/// Compiled functions take the WASM stack pointer and a `*const RuntimeContext`, and return the
+/// new stack pointer. Every instance-specific address is read from the context at runtime, so the
+/// result only depends on the function's type and body and can be shared between instances.
 pub(crate) fn compile_wasm(
-    context: &WasmContext,
-    ty: &FuncType,
+    index: u32,
     body: FunctionBody,
+    module: &ModuleTypes,
+    options: &CompilerOptions,
 ) -> Result<JitFn> {
-    let locals = read_local_types(ty, &body).unwrap();
+    let ty = module.function_type(index)?;
+    let mut locals = read_local_types(ty, &body)?;
     let param_count = ty.params().len();
 
+    // Validating the body gives the deepest its operand stack gets, which is what the function
+    // needs on the WASM stack beyond its locals. Every supported value is one word.
+    let type_index = module.functions[index as usize];
+    let mut validator = FuncToValidate::new(index, type_index, module, &FEATURES)
+        .into_validator(Default::default());
+    let mut reader = body.get_binary_reader();
+    validator.read_locals(&mut reader)?;
+    let mut max_operands = 0;
+
+    // Every try block gets two hidden locals after the real ones, for the stack pointer to restore
+    // in its catch clauses and the exception they caught
+    let mut try_blocks = 0;
+    let mut depth = 0;
+    let mut max_depth = 0;
+    while !reader.eof() {
+        let offset = reader.original_position();
+        let op = reader.read_operator()?;
+        validator.op(offset, &op)?;
+        max_operands = max_operands.max(validator.operand_stack_height());
+
+        match op {
+            Operator::Try { .. } => {
+                try_blocks += 1;
+                depth += 1;
+            }
+            Operator::Block { .. } | Operator::Loop { .. } | Operator::If { .. } => depth += 1,
+            Operator::End | Operator::Delegate { .. } => depth -= 1,
+            _ => continue,
+        }
+        max_depth = max_depth.max(depth);
+    }
+    validator.finish(reader.original_position())?;
+    let mut next_try_slot = locals.len() as u32;
+    try_reserve(&mut locals, try_blocks as usize * 2)?;
+    locals.extend(repeat_n(ValType::I32, try_blocks as usize * 2));
+
     let mut func = Emitter::new();
-    func.mov(r1, ARCH_SP); // We need to save the high registers
-    func.mov(r2, MODULE);
-    func.push(register_list!(lr, r1, r2, MEMORY, GLOBALS, LOCALS)); // Save the link register and locals register
+    func.mov(r2, ARCH_SP); // We need to save the high registers
+    func.mov(r3, WASM_SP);
+    // Save the link register and locals register, r1 only pads the frame to keep sp 8-byte aligned
+    func.push(register_list!(
+        lr, r1, r2, r3, CONTEXT, MEMORY, GLOBALS, LOCALS
+    ));
+    func.movs(CONTEXT, r1); // Keep the context pointer for the rest of the function
+
+    let mut emitted_data: BTreeMap<u32, Label> = BTreeMap::new();
+    let zeroed_locals = (locals.len() - param_count) as u32;
+
+    // Trap before zeroing the locals if they would run past the end of the WASM stack. Nothing is
+    // set up yet, so the trap leaves through `stack_overflow` rather than the unwind path.
+    let mut stack_overflow = func.create_label();
+    let mut overflow = func.create_label();
+    let mut fits = func.create_label();
+    load_index(
+        &mut func,
+        &mut emitted_data,
+        r1,
+        (zeroed_locals + max_operands + HELPER_STACK) * 4,
+    );
+    func.subs(r1, Sub2(r0, r1));
+    func.b_if(Condition::CC, overflow); // Borrowed, so it can't fit
+    load_context_field(&mut func, r2, ContextField::StackLimit);
+    func.cmp(r1, r2);
+    func.b_if(Condition::CS, fits);
+    func.label(&mut overflow);
+    raise_trap(&mut func, Trap::StackOverflow, stack_overflow);
+    func.label(&mut fits);
 
     // We need to zero non-param locals
-    // TODO: Find most efficient way to do this
     func.movs(r1, 0); // Zero register
-    for _ in param_count..locals.len() {
+    if zeroed_locals <= 8 {
+        for _ in 0..zeroed_locals {
+            func.subs(r0, 4);
+            func.str(r1, r0);
+        }
+    } else {
+        // Too many to unroll without bloating the function
+        match u8::try_from(zeroed_locals) {
+            Ok(count) => func.movs(r2, count),
+            Err(_) => {
+                let count = get_data_label(&mut func, &mut emitted_data, zeroed_locals);
+                func.ldr(r2, count);
+            }
+        }
+
+        let mut zero_loop = func.create_label();
+        func.label(&mut zero_loop);
         func.subs(r0, 4);
         func.str(r1, r0);
+        func.subs(r2, 1);
+        func.b_if(Condition::NE, zero_loop);
     }
 
-    let module_ptr = func.data(context.module_ptr as u32);
-    let memory_ptr = func.data(context.memory_ptr as u32);
-    let globals_ptr = func.data(context.global_ptr as u32);
-
-    #[allow(clippy::fn_to_numeric_cast_with_truncation)] // ARMv6-M is a 32-bit architecture
-    let call_func = func.data(context.call_func as u32);
-
-    func.ldr(r1, module_ptr); // Load module ptr
-    func.mov(MODULE, r1); // Move module ptr into high register
-    func.ldr(MEMORY, memory_ptr); // Load memory ptr
-    func.ldr(GLOBALS, globals_ptr); // Load global ptr
+    load_context_field(&mut func, MEMORY, ContextField::Memory); // Load memory ptr
+    load_context_field(&mut func, GLOBALS, ContextField::Globals); // Load global ptr
     func.mov(ARCH_SP, sp); // Save the original stack pointer
     func.mov(sp, r0); // Move the WASM stack pointer into sp
     func.mov(LOCALS, r0); // Move the start of the locals into r0
 
-    let mut scope_stack: Vec<Scope> = vec![];
-    let mut emitted_data: BTreeMap<u32, Label> = BTreeMap::new();
+    // Reserved up front so that entering a block never allocates
+    let mut scope_stack: Vec<Scope> = Vec::new();
+    try_reserve(&mut scope_stack, max_depth as usize + 1)?;
 
     let mut func_end = func.create_label();
+    let mut func_return = func.create_label();
+    let mut func_unwind = func.create_label();
     scope_stack.push(Scope::Block(func_end)); // Function end counts as a block that we can break out of
 
     // func.bkpt();
@@ -124,21 +373,34 @@ pub(crate) fn compile_wasm(
             func.label(&mut next_code_section);
         }
 
-        match op? {
+        let op = op?;
+        // Traps and exceptions go to the innermost try block, if there is one
+        let unwind = current_handler(&scope_stack, func_unwind);
+        let canonicalize = options.canonicalize_nans
+            && matches!(
+                op,
+                Operator::F32Add
+                    | Operator::F32Sub
+                    | Operator::F32Mul
+                    | Operator::F32Div
+                    | Operator::F32Sqrt
+                    | Operator::F32Min
+                    | Operator::F32Max
+                    | Operator::F32Ceil
+                    | Operator::F32Floor
+                    | Operator::F32Trunc
+                    | Operator::F32Nearest
+            );
+
+        match op {
             // Control flow operators
-            Operator::Unreachable => {
-                // Doing this here for simplicity
-                let panic = get_data_label(&mut func, &mut emitted_data, wasm_panic as u32);
-                func.movs(A, 0);
-                func.ldr(B, panic);
-                func.blx(B);
-            }
+            Operator::Unreachable => raise_trap(&mut func, Trap::Unreachable, unwind),
             Operator::Block { .. } => block(&mut func, &mut scope_stack),
             Operator::Loop { .. } => r#loop(&mut func, &mut scope_stack),
             Operator::Nop => (), // Do nothing
             Operator::If { .. } => r#if(&mut func, &mut scope_stack),
             Operator::Else => r#else(&mut func, &mut scope_stack),
-            Operator::End => end(&mut func, &mut scope_stack),
+            Operator::End => end(&mut func, &mut scope_stack, func_unwind),
             Operator::Br { relative_depth } => br(&mut func, &scope_stack, relative_depth),
             Operator::BrIf { relative_depth } => br_if(&mut func, &scope_stack, relative_depth),
             Operator::BrTable { targets } => br_table(
@@ -150,25 +412,128 @@ pub(crate) fn compile_wasm(
                 targets.default(),
             ),
             Operator::Return => r#return(&mut func, &scope_stack),
-            Operator::Call { function_index } => call(&mut func, &call_func, function_index),
-            Operator::Drop => func.add(sp, u7::new(4)), // So simple that splitting it out into a function is overkill
-            Operator::Select => select(&mut func),
+            Operator::Call { function_index } => {
+                call(&mut func, &mut emitted_data, function_index, unwind)
+            }
+            Operator::CallIndirect {
+                type_index,
+                table_index,
+                ..
+            } => call_indirect(
+                &mut func,
+                &mut emitted_data,
+                type_index,
+                table_index,
+                unwind,
+            ),
+            Operator::ReturnCall { function_index } => {
+                let params = module.function_type(function_index)?.len_inputs();
+                drop_caught(&mut func, &mut emitted_data, try_blocks);
+                load_index(&mut func, &mut emitted_data, r0, function_index);
+                return_call(
+                    &mut func,
+                    &mut emitted_data,
+                    locals.len(),
+                    params,
+                    func_return,
+                );
+            }
+            Operator::ReturnCallIndirect {
+                type_index,
+                table_index,
+            } => {
+                let params = module.type_at(type_index)?.len_inputs();
+                // Before the call is resolved, which may unwind and drop them again
+                drop_caught(&mut func, &mut emitted_data, try_blocks);
+                resolve_indirect(
+                    &mut func,
+                    &mut emitted_data,
+                    type_index,
+                    table_index,
+                    unwind,
+                );
+                return_call(
+                    &mut func,
+                    &mut emitted_data,
+                    locals.len(),
+                    params,
+                    func_return,
+                );
+            }
+            Operator::Try { blockty } => {
+                let params = module.block_params(blockty)?;
+                r#try(
+                    &mut func,
+                    &mut emitted_data,
+                    &mut scope_stack,
+                    &locals,
+                    next_try_slot,
+                    params,
+                )?;
+                next_try_slot += 2;
+            }
+            Operator::Catch { tag_index } => {
+                let payload = module.tag_payload(tag_index)?;
+                catch(
+                    &mut func,
+                    &mut emitted_data,
+                    &mut scope_stack,
+                    &locals,
+                    Some(tag_index),
+                    payload,
+                )?
+            }
+            Operator::CatchAll => catch(
+                &mut func,
+                &mut emitted_data,
+                &mut scope_stack,
+                &locals,
+                None,
+                0,
+            )?,
+            Operator::Throw { tag_index } => {
+                let payload = module.tag_payload(tag_index)?;
+                throw(&mut func, &mut emitted_data, tag_index, payload, unwind)
+            }
+            Operator::Rethrow { relative_depth } => rethrow(
+                &mut func,
+                &mut emitted_data,
+                &scope_stack,
+                &locals,
+                relative_depth,
+                unwind,
+            )?,
+            Operator::Delegate { relative_depth } => {
+                delegate(&mut func, &mut scope_stack, relative_depth, func_unwind)
+            }
+            Operator::Drop => func.add(sp, u7::new(1)), // The immediate is in words, so this drops one value
+            Operator::Select | Operator::TypedSelect { .. } => select(&mut func),
 
             // Local variable operators
-            Operator::LocalGet { local_index } => local_get(&mut func, &locals, local_index)?,
-            Operator::LocalSet { local_index } => local_set(&mut func, &locals, local_index)?,
-            Operator::LocalTee { local_index } => local_tee(&mut func, &locals, local_index)?,
+            Operator::LocalGet { local_index } => {
+                local_get(&mut func, &mut emitted_data, &locals, local_index)?
+            }
+            Operator::LocalSet { local_index } => {
+                local_set(&mut func, &mut emitted_data, &locals, local_index)?
+            }
+            Operator::LocalTee { local_index } => {
+                local_tee(&mut func, &mut emitted_data, &locals, local_index)?
+            }
 
             // Global variable operators
-            Operator::GlobalGet { global_index } => global_get(&mut func, global_index),
-            Operator::GlobalSet { global_index } => global_set(&mut func, global_index),
+            Operator::GlobalGet { global_index } => {
+                global_get(&mut func, &mut emitted_data, module.globals, global_index)?
+            }
+            Operator::GlobalSet { global_index } => {
+                global_set(&mut func, &mut emitted_data, module.globals, global_index)?
+            }
 
             // Memory operators
             Operator::I32Load { memarg } => x32_load(&mut func, &memarg, &mut emitted_data),
             Operator::F32Load { memarg } => x32_load(&mut func, &memarg, &mut emitted_data),
-            // Operator::I32Load8S { memarg } => todo!("I32Load8S"),
+            Operator::I32Load8S { memarg } => i32_load8_s(&mut func, &memarg, &mut emitted_data),
             Operator::I32Load8U { memarg } => i32_load8_u(&mut func, &memarg, &mut emitted_data),
-            // Operator::I32Load16S { memarg } => todo!("I32Load16S"),
+            Operator::I32Load16S { memarg } => i32_load16_s(&mut func, &memarg, &mut emitted_data),
             Operator::I32Load16U { memarg } => i32_load16_u(&mut func, &memarg, &mut emitted_data),
             Operator::I32Store { memarg } => x32_store(&mut func, &memarg, &mut emitted_data),
             Operator::F32Store { memarg } => x32_store(&mut func, &memarg, &mut emitted_data),
@@ -188,16 +553,16 @@ pub(crate) fn compile_wasm(
             Operator::I32LeU => i32_le_u(&mut func),
             Operator::I32GeS => i32_ge_s(&mut func),
             Operator::I32GeU => i32_ge_u(&mut func),
-            Operator::I32Clz => todo!("I32Clz"),
-            Operator::I32Ctz => todo!("I32Ctz"),
-            Operator::I32Popcnt => todo!("I32Popcnt"),
+            Operator::I32Clz => i32_clz(&mut func),
+            Operator::I32Ctz => i32_ctz(&mut func),
+            Operator::I32Popcnt => i32_popcnt(&mut func),
             Operator::I32Add => i32_add(&mut func),
             Operator::I32Sub => i32_sub(&mut func),
             Operator::I32Mul => i32_mul(&mut func),
-            Operator::I32DivS => i32_div_s(&mut func, &mut emitted_data),
-            Operator::I32DivU => i32_div_u(&mut func, &mut emitted_data),
-            Operator::I32RemS => i32_rem_s(&mut func, &mut emitted_data),
-            Operator::I32RemU => i32_rem_u(&mut func, &mut emitted_data),
+            Operator::I32DivS => i32_div_s(&mut func, unwind),
+            Operator::I32DivU => i32_div_u(&mut func, unwind),
+            Operator::I32RemS => i32_rem_s(&mut func, unwind),
+            Operator::I32RemU => i32_rem_u(&mut func, unwind),
             Operator::I32And => i32_and(&mut func),
             Operator::I32Or => i32_or(&mut func),
             Operator::I32Xor => i32_xor(&mut func),
@@ -209,98 +574,126 @@ pub(crate) fn compile_wasm(
 
             // F32 operators
             Operator::F32Const { value } => f32_const(&mut func, &mut emitted_data, value),
-            Operator::F32Eq => f32_eq(&mut func, &mut emitted_data),
-            Operator::F32Ne => f32_ne(&mut func, &mut emitted_data),
-            Operator::F32Lt => f32_lt(&mut func, &mut emitted_data),
-            Operator::F32Gt => f32_gt(&mut func, &mut emitted_data),
-            Operator::F32Le => f32_le(&mut func, &mut emitted_data),
-            Operator::F32Ge => f32_ge(&mut func, &mut emitted_data),
-            Operator::F32Abs => todo!("F32Abs"),
-            Operator::F32Neg => todo!("F32Neg"),
-            Operator::F32Ceil => todo!("F32Ceil"),
-            Operator::F32Floor => todo!("F32Floor"),
-            Operator::F32Trunc => todo!("F32Trunc"),
-            Operator::F32Nearest => todo!("F32Nearest"),
-            Operator::F32Sqrt => f32_sqrt(&mut func, &mut emitted_data),
-            Operator::F32Add => f32_add(&mut func, &mut emitted_data),
-            Operator::F32Sub => f32_sub(&mut func, &mut emitted_data),
-            Operator::F32Mul => f32_mul(&mut func, &mut emitted_data),
-            Operator::F32Div => f32_div(&mut func, &mut emitted_data),
-            Operator::F32Min => f32_min(&mut func, &mut emitted_data),
-            Operator::F32Max => f32_max(&mut func, &mut emitted_data),
-            Operator::F32Copysign => todo!("F32Copysign"),
+            Operator::F32Eq => f32_eq(&mut func),
+            Operator::F32Ne => f32_ne(&mut func),
+            Operator::F32Lt => f32_lt(&mut func),
+            Operator::F32Gt => f32_gt(&mut func),
+            Operator::F32Le => f32_le(&mut func),
+            Operator::F32Ge => f32_ge(&mut func),
+            Operator::F32Abs => f32_abs(&mut func),
+            Operator::F32Neg => f32_neg(&mut func),
+            Operator::F32Ceil => f32_ceil(&mut func),
+            Operator::F32Floor => f32_floor(&mut func),
+            Operator::F32Trunc => f32_trunc(&mut func),
+            Operator::F32Nearest => f32_nearest(&mut func),
+            Operator::F32Sqrt => f32_sqrt(&mut func),
+            Operator::F32Add => f32_add(&mut func),
+            Operator::F32Sub => f32_sub(&mut func),
+            Operator::F32Mul => f32_mul(&mut func),
+            Operator::F32Div => f32_div(&mut func),
+            Operator::F32Min => f32_min(&mut func),
+            Operator::F32Max => f32_max(&mut func),
+            Operator::F32Copysign => f32_copysign(&mut func),
 
             // Conversion operators
-            Operator::I32WrapI64 => todo!("I32WrapI64"),
-            Operator::I32TruncF32S => i32_trunc_f32_s(&mut func, &mut emitted_data),
-            Operator::I32TruncF32U => todo!("I32TruncF32U"),
-            Operator::I32TruncF64S => todo!("I32TruncF64S"),
-            Operator::I32TruncF64U => todo!("I32TruncF64U"),
+            op @ (Operator::I32WrapI64 | Operator::I32TruncF64S | Operator::I32TruncF64U) => {
+                return Err(WasmError::UnsupportedOp(format!("{:?}", op)))
+            }
+            Operator::I32TruncF32S => i32_trunc_f32_s(&mut func, unwind),
+            Operator::I32TruncF32U => i32_trunc_f32_u(&mut func, unwind),
             Operator::I32Extend8S => i32_extend8_s(&mut func),
             Operator::I32Extend16S => i32_extend16_s(&mut func),
-            Operator::F32ConvertI32S => f32_convert_i32_s(&mut func, &mut emitted_data),
-            Operator::F32ConvertI32U => f32_convert_i32_u(&mut func, &mut emitted_data),
-            Operator::I32ReinterpretF32 => todo!("I32ReinterpretF32"),
+            Operator::I32TruncSatF32S => i32_trunc_sat_f32_s(&mut func),
+            Operator::I32TruncSatF32U => i32_trunc_sat_f32_u(&mut func),
+            Operator::F32ConvertI32S => f32_convert_i32_s(&mut func),
+            Operator::F32ConvertI32U => f32_convert_i32_u(&mut func),
+            Operator::I32ReinterpretF32 | Operator::F32ReinterpretI32 => (), // Both are just bits on the stack
 
             // Bulk memory operators
-            Operator::MemoryCopy { .. } => memory_copy(&mut func, &mut emitted_data),
-            Operator::MemoryGrow { .. } => {
-                // Grow is not supported, but emit it anyway so that we can use programs containing it
-                // as long as they don't actually call it with a non-zero argument.
-                func.pop(register_list!(A));
-                func.cmp(A, 0);
-                let mut zero = func.create_label();
-                func.b_if(Condition::EQ, zero);
-                let panic = get_data_label(&mut func, &mut emitted_data, wasm_panic as u32);
-                func.movs(A, 1);
-                func.ldr(B, panic);
-                func.blx(B);
-                func.label(&mut zero);
-                func.movs(A, 1);
-                func.push(register_list!(A));
+            Operator::MemoryCopy { dst_mem, src_mem } => {
+                memory_copy(&mut func, &mut emitted_data, dst_mem, src_mem, unwind)
             }
-            Operator::MemorySize { .. } => {
-                func.movs(A, context.memory_size as u8);
-                func.push(register_list!(A));
+            Operator::MemoryGrow { mem, .. } => memory_grow(&mut func, &mut emitted_data, mem),
+            Operator::MemorySize { mem, .. } => memory_size(&mut func, &mut emitted_data, mem),
+            Operator::MemoryFill { mem } => memory_fill(&mut func, &mut emitted_data, mem, unwind),
+            Operator::MemoryInit { data_index, mem } => {
+                memory_init(&mut func, &mut emitted_data, data_index, mem, unwind)
+            }
+            Operator::DataDrop { data_index } => {
+                data_drop(&mut func, &mut emitted_data, data_index)
             }
-            Operator::MemoryFill { .. } => memory_fill(&mut func, &mut emitted_data),
-            op => return Err(WasmError::UnsupportedOp(format!("{:?}", op))),
-        }
-    }
 
-    func.label(&mut func_end); // Label for the end of the function
-    func.ldr(r1, sp); // Load the top value of the stack into r1
-    match ty.len_outputs() {
-        0 => {
-            func.adds(LOCALS, locals.len() as u8 * 4); // Move the locals pointer back to the top of the stack
-        }
-        1 => {
-            if locals.len() == 0 {
-                func.subs(LOCALS, 4); // Create space for the return value
-            } else {
-                if let Ok(offset) = u8::try_from((locals.len() - 1) * 4) {
-                    func.adds(LOCALS, offset)
-                } else {
-                    func.movs(r0, (locals.len() - 1) as u8);
-                    func.lsl(r0, ImmShift(r0, u5::new(2)));
-                    func.adds(LOCALS, r0);
-                }
-                // func.adds(LOCALS, (locals.len() - 1) as u8 * 4); // Move the locals pointer to the first local
+            // Atomic operators, loads, stores and read-modify-writes are handled below
+            Operator::MemoryAtomicNotify { memarg } => {
+                memory_atomic_notify(&mut func, &mut emitted_data, &memarg, unwind)
+            }
+            // The timeout is an i64, which the compiler doesn't support yet
+            op @ (Operator::MemoryAtomicWait32 { .. } | Operator::MemoryAtomicWait64 { .. }) => {
+                return Err(WasmError::UnsupportedOp(format!("{:?}", op)))
             }
-            func.str(r1, LOCALS); // Overwrite the first local with the top value of the stack to return
+            Operator::AtomicFence => (), // Nothing runs alongside us, so there is nothing to order
+
+            // Reference operators, null is 0 and functions are stored as their index plus one
+            Operator::RefNull { .. } => i32_const(&mut func, &mut emitted_data, 0),
+            Operator::RefFunc { function_index } => {
+                i32_const(&mut func, &mut emitted_data, function_index as i32 + 1)
+            }
+            Operator::RefIsNull => ref_is_null(&mut func),
+
+            // Table operators
+            Operator::TableGet { table } => table_get(&mut func, &mut emitted_data, table, unwind),
+            Operator::TableSet { table } => table_set(&mut func, &mut emitted_data, table, unwind),
+            Operator::TableSize { table } => table_size(&mut func, &mut emitted_data, table),
+            Operator::TableGrow { table } => table_grow(&mut func, &mut emitted_data, table),
+            Operator::TableFill { table } => {
+                table_fill(&mut func, &mut emitted_data, table, unwind)
+            }
+            Operator::TableCopy {
+                dst_table,
+                src_table,
+            } => table_copy(&mut func, &mut emitted_data, dst_table, src_table, unwind),
+            Operator::TableInit { elem_index, table } => {
+                table_init(&mut func, &mut emitted_data, elem_index, table, unwind)
+            }
+            Operator::ElemDrop { elem_index } => {
+                elem_drop(&mut func, &mut emitted_data, elem_index)
+            }
+            op => match atomic_access_kind(&op) {
+                Some((memarg, kind, size)) => {
+                    atomic_access_op(&mut func, &mut emitted_data, &memarg, kind, size, unwind)
+                }
+                None => return Err(WasmError::UnsupportedOp(format!("{:?}", op))),
+            },
         }
-        len => {
-            panic!("Unsupported number of return values: {}", len) // Since we ensure WASM v1, this should (theoretically) never happen
+
+        if canonicalize {
+            canonicalize_nan(&mut func);
         }
     }
 
+    func.label(&mut func_end); // Label for the end of the function
+    drop_caught(&mut func, &mut emitted_data, try_blocks);
+    store_results(&mut func, &mut emitted_data, locals.len(), ty.len_outputs());
+
+    func.label(&mut func_return);
     func.mov(sp, ARCH_SP); // Restore sp
     func.mov(r0, LOCALS); // Move the locals pointer into r0 for return
 
-    func.pop(register_list!(r1, r2, MEMORY, GLOBALS, LOCALS)); // Restore the link register and locals register
+    func.pop(register_list!(r1, r2, r3, CONTEXT, MEMORY, GLOBALS, LOCALS)); // Restore the link register and locals register
 
-    func.mov(ARCH_SP, r1); // CORRECTLY restores the high registers
-    func.mov(MODULE, r2);
+    func.mov(ARCH_SP, r2); // CORRECTLY restores the high registers
+    func.mov(WASM_SP, r3);
     func.pop(register_list!(pc)); // Return
-    Ok(func.build())
+
+    // A callee failed, return straight to our caller which will do the same
+    func.label(&mut func_unwind);
+    drop_caught(&mut func, &mut emitted_data, try_blocks);
+    func.mov(sp, ARCH_SP);
+    func.label(&mut stack_overflow);
+    func.pop(register_list!(r1, r2, r3, CONTEXT, MEMORY, GLOBALS, LOCALS));
+    func.mov(ARCH_SP, r2);
+    func.mov(WASM_SP, r3);
+    func.pop(register_list!(pc));
+
+    func.try_build().map_err(|_| WasmError::OutOfMemory)
 }
@@ -0,0 +1,259 @@
+use crate::generation::{
+    atomics::*, bulk_memory::*, control_flow::resolve_indirect_call, conversion::*, exceptions::*,
+    f32_ops::*, i32_ops::*, memory::*, tables::*,
+};
+
+/// Word offsets of the fixed fields in `RuntimeContext`, as seen by compiled code
+#[repr(u8)]
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum ContextField {
+    Module = 0,
+    CallFunc = 1,
+    Memory = 2,
+    Globals = 3,
+    MemorySize = 4,
+    Unwind = 5,
+    TailCall = 17,
+    Memories = 18,
+    StackLimit = 20,
+}
+
+/// Runtime functions that compiled code calls through the helper table
+///
+/// The discriminant is the index into `RuntimeContext::helpers`, which compiled code has built in.
+/// Adding, removing or reordering helpers changes the layout, so it needs a `COMPILER_VERSION`
+/// bump like any other change to the generated code.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum Helper {
+    Load32Unaligned,
+    Store32Unaligned,
+    Idiv,
+    Uidiv,
+    Idivmod,
+    Uidivmod,
+    Fadd,
+    Fsub,
+    Fmul,
+    Fdiv,
+    Fsqrt,
+    I2f,
+    Ui2f,
+    Clz,
+    Ctz,
+    Popcnt,
+    Ceilf,
+    Floorf,
+    Truncf,
+    Nearestf,
+    F32ToI32,
+    F32ToU32,
+    F32ToI32Sat,
+    F32ToU32Sat,
+    Fordering,
+    Fmin,
+    Fmax,
+    MemoryCopy,
+    MemoryFill,
+    MemoryInit,
+    DataDrop,
+    TableGet,
+    TableSet,
+    TableSize,
+    TableGrow,
+    TableFill,
+    TableCopy,
+    TableInit,
+    ElemDrop,
+    ResolveIndirectCall,
+    Throw,
+    CatchException,
+    Rethrow,
+    DropExceptions,
+    AtomicAccess,
+    AtomicNotify,
+}
+
+pub(crate) const HELPER_COUNT: usize = Helper::AtomicNotify as usize + 1;
+
+/// Word offset of the first helper in `RuntimeContext`
+pub(crate) const HELPERS_OFFSET: usize = 21;
+
+impl Helper {
+    /// Word offset of this helper's address in `RuntimeContext`
+    pub(crate) fn context_offset(self) -> usize {
+        HELPERS_OFFSET + self as usize
+    }
+
+    fn address(self) -> u32 {
+        use rp_pico::hal::rom_data::float_funcs::fsqrt;
+
+        match self {
+            Helper::Load32Unaligned => load32_unaligned as *const () as u32,
+            Helper::Store32Unaligned => store32_unaligned as *const () as u32,
+            Helper::Idiv => __aeabi_idiv as *const () as u32,
+            Helper::Uidiv => __aeabi_uidiv as *const () as u32,
+            Helper::Idivmod => __aeabi_idivmod as *const () as u32,
+            Helper::Uidivmod => __aeabi_uidivmod as *const () as u32,
+            Helper::Fadd => __aeabi_fadd as *const () as u32,
+            Helper::Fsub => __aeabi_fsub as *const () as u32,
+            Helper::Fmul => __aeabi_fmul as *const () as u32,
+            Helper::Fdiv => __aeabi_fdiv as *const () as u32,
+            Helper::Fsqrt => fsqrt as *const () as u32,
+            Helper::I2f => __aeabi_i2f as *const () as u32,
+            Helper::Ui2f => __aeabi_ui2f as *const () as u32,
+            Helper::Clz => count_leading_zeros as *const () as u32,
+            Helper::Ctz => count_trailing_zeros as *const () as u32,
+            Helper::Popcnt => count_ones as *const () as u32,
+            Helper::Ceilf => ceilf as *const () as u32,
+            Helper::Floorf => floorf as *const () as u32,
+            Helper::Truncf => truncf as *const () as u32,
+            Helper::Nearestf => nearestf as *const () as u32,
+            Helper::F32ToI32 => f32_to_i32 as *const () as u32,
+            Helper::F32ToU32 => f32_to_u32 as *const () as u32,
+            Helper::F32ToI32Sat => f32_to_i32_sat as *const () as u32,
+            Helper::F32ToU32Sat => f32_to_u32_sat as *const () as u32,
+            Helper::Fordering => f32_ordering as *const () as u32,
+            Helper::Fmin => minf as *const () as u32,
+            Helper::Fmax => maxf as *const () as u32,
+            Helper::MemoryCopy => memory_copy_checked as *const () as u32,
+            Helper::MemoryFill => memory_fill_checked as *const () as u32,
+            Helper::MemoryInit => memory_init_checked as *const () as u32,
+            Helper::DataDrop => drop_data_segment as *const () as u32,
+            Helper::TableGet => table_get_checked as *const () as u32,
+            Helper::TableSet => table_set_checked as *const () as u32,
+            Helper::TableSize => current_table_size as *const () as u32,
+            Helper::TableGrow => grow_table as *const () as u32,
+            Helper::TableFill => table_fill_checked as *const () as u32,
+            Helper::TableCopy => table_copy_checked as *const () as u32,
+            Helper::TableInit => table_init_checked as *const () as u32,
+            Helper::ElemDrop => drop_element_segment as *const () as u32,
+            Helper::ResolveIndirectCall => resolve_indirect_call as *const () as u32,
+            Helper::Throw => throw_exception as *const () as u32,
+            Helper::CatchException => catch_exception as *const () as u32,
+            Helper::Rethrow => rethrow_exception as *const () as u32,
+            Helper::DropExceptions => drop_exceptions as *const () as u32,
+            Helper::AtomicAccess => atomic_access as *const () as u32,
+            Helper::AtomicNotify => atomic_notify as *const () as u32,
+        }
+    }
+}
+
+/// A data segment as seen by `memory.init`, pointing into the module bytes
+///
+/// Active segments and dropped passive segments have a length of 0, as the spec requires.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct DataSegment {
+    pub(crate) data: *const u8,
+    pub(crate) len: u32,
+}
+
+/// A linear memory as seen by compiled code, one per memory index
+///
+/// Memory 0 is also in the `memory` fields of `RuntimeContext`, so the common case needs no lookup.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct MemoryRegion {
+    pub(crate) base: u32,
+    /// Size in bytes
+    pub(crate) length: u32,
+    /// Size in pages
+    pub(crate) size: u32,
+}
+
+impl MemoryRegion {
+    /// Size of a region in bytes, the stride of the table compiled code indexes
+    pub(crate) const SIZE: u32 = 12;
+
+    /// Checks that `len` bytes starting at `start` are inside the memory
+    pub(crate) fn contains(&self, start: u64, len: u64) -> bool {
+        start + len <= self.length as u64
+    }
+}
+
+/// Everything compiled code needs to know about the instance it is running in
+///
+/// Compiled functions receive a pointer to this in `CONTEXT` and reach every absolute address
+/// through it, so the generated code itself only depends on the module bytes. All fields are
+/// words so that the layout (and therefore the generated code) is the same on every target.
+#[repr(C)]
+#[derive(Debug)]
+pub(crate) struct RuntimeContext {
+    pub(crate) module: u32,
+    pub(crate) call_func: u32,
+    pub(crate) memory: u32,
+    pub(crate) globals: u32,
+    pub(crate) memory_size: u32,
+    /// Non-zero while compiled frames are being unwound back to the host
+    pub(crate) unwind: u32,
+    /// Size of the memory in bytes
+    pub(crate) memory_length: u32,
+    /// Pointer to the module's `DataSegment`s
+    pub(crate) data_segments: u32,
+    pub(crate) data_segment_count: u32,
+    /// Pointer to the instance's `WasmTable`s
+    pub(crate) tables: u32,
+    pub(crate) table_count: u32,
+    /// Pointer to the module's element segments, each a `Box<[u32]>` of references
+    pub(crate) element_segments: u32,
+    pub(crate) element_segment_count: u32,
+    /// Pointer to the type id of every function, used to check indirect calls
+    pub(crate) function_types: u32,
+    pub(crate) function_count: u32,
+    /// Pointer to the type id of every type index, equal types share an id
+    pub(crate) type_ids: u32,
+    pub(crate) type_count: u32,
+    /// Index plus one of the function to call once the current one has returned, set by tail calls
+    pub(crate) tail_call: u32,
+    /// Pointer to a `MemoryRegion` for every memory
+    pub(crate) memories: u32,
+    pub(crate) memory_count: u32,
+    /// Lowest address of the WASM stack, which grows down towards it
+    pub(crate) stack_limit: u32,
+    pub(crate) helpers: [u32; HELPER_COUNT],
+}
+
+impl RuntimeContext {
+    pub(crate) fn new() -> Self {
+        let mut helpers = [0; HELPER_COUNT];
+        for (index, address) in helpers.iter_mut().enumerate() {
+            // SAFETY: Helper is repr(u8) and every index below HELPER_COUNT is a valid variant
+            let helper: Helper = unsafe { core::mem::transmute(index as u8) };
+            *address = helper.address();
+        }
+
+        RuntimeContext {
+            module: 0,
+            call_func: 0,
+            memory: 0,
+            globals: 0,
+            memory_size: 0,
+            unwind: 0,
+            memory_length: 0,
+            data_segments: 0,
+            data_segment_count: 0,
+            tables: 0,
+            table_count: 0,
+            element_segments: 0,
+            element_segment_count: 0,
+            function_types: 0,
+            function_count: 0,
+            type_ids: 0,
+            type_count: 0,
+            tail_call: 0,
+            memories: 0,
+            memory_count: 0,
+            stack_limit: 0,
+            helpers,
+        }
+    }
+
+    /// The memory with the given index, which validation guarantees exists
+    pub(crate) fn memory_region(&self, index: u32) -> &MemoryRegion {
+        assert!(index < self.memory_count, "Memory {} does not exist", index);
+
+        // SAFETY: The index was checked against the table, which the module keeps in place
+        unsafe { &*(self.memories as *const MemoryRegion).add(index as usize) }
+    }
+}
@@ -0,0 +1,241 @@
+use alloc::collections::BTreeMap;
+use core::ptr;
+use core::slice;
+use cortex_m::interrupt;
+use pico_emit::{emitter::Label, instructions::*, register_list, Emitter};
+use wasmparser_nostd::{MemArg, Operator};
+
+use crate::aliases::*;
+use crate::context::{Helper, MemoryRegion, RuntimeContext};
+use crate::wasm_module::{Trap, WasmModule};
+
+use super::{call_with_operands, check_trap, trapping_result};
+
+// There is only one core running WASM, so masking interrupts with PRIMASK is enough to make an
+// access atomic. The helpers below do the whole access inside `interrupt::free`.
+
+/// What `atomic_access` does, it is passed along with the log2 of the access size
+#[repr(u32)]
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum AtomicOp {
+    Load = 0,
+    Store = 1,
+    Add = 2,
+    Sub = 3,
+    And = 4,
+    Or = 5,
+    Xor = 6,
+    Xchg = 7,
+    Cmpxchg = 8,
+}
+
+impl AtomicOp {
+    fn from_code(code: u32) -> AtomicOp {
+        match code {
+            0 => AtomicOp::Load,
+            1 => AtomicOp::Store,
+            2 => AtomicOp::Add,
+            3 => AtomicOp::Sub,
+            4 => AtomicOp::And,
+            5 => AtomicOp::Or,
+            6 => AtomicOp::Xor,
+            7 => AtomicOp::Xchg,
+            8 => AtomicOp::Cmpxchg,
+            _ => unreachable!("Unknown atomic operation {}", code),
+        }
+    }
+
+    /// Number of operands on the WASM stack, the address is always the first
+    fn operands(self) -> usize {
+        match self {
+            AtomicOp::Load => 1,
+            AtomicOp::Cmpxchg => 3,
+            _ => 2,
+        }
+    }
+}
+
+// Adds the static offset to an address and checks the access against the memory and for natural
+// alignment, in that order as the spec requires
+fn effective_address(
+    memory: &MemoryRegion,
+    address: u32,
+    offset: u32,
+    size: u32,
+) -> Result<usize, Trap> {
+    let address = address as u64 + offset as u64;
+    if !memory.contains(address, size as u64) {
+        return Err(Trap::OutOfBoundsMemoryAccess);
+    }
+
+    if !address.is_multiple_of(size as u64) {
+        return Err(Trap::UnalignedAtomic);
+    }
+
+    Ok(address as usize)
+}
+
+// SAFETY: `address` must be in bounds and aligned to `size`
+unsafe fn read(memory: &MemoryRegion, address: usize, size: u32) -> u32 {
+    let memory = (memory.base as *const u8).add(address);
+    match size {
+        1 => ptr::read_volatile(memory) as u32,
+        2 => ptr::read_volatile(memory as *const u16) as u32,
+        _ => ptr::read_volatile(memory as *const u32),
+    }
+}
+
+// SAFETY: `address` must be in bounds and aligned to `size`
+unsafe fn write(memory: &MemoryRegion, address: usize, size: u32, value: u32) {
+    let memory = (memory.base as *mut u8).add(address);
+    match size {
+        1 => ptr::write_volatile(memory, value as u8),
+        2 => ptr::write_volatile(memory as *mut u16, value as u16),
+        _ => ptr::write_volatile(memory as *mut u32, value),
+    }
+}
+
+/// Every atomic load, store and read-modify-write, `args` points at its operands on the WASM stack
+///
+/// `op` is an `AtomicOp` shifted left by 2, ored with the log2 of the access size, with the memory
+/// index above that from bit 8. Returns the value loaded or the old value for read-modify-writes.
+pub(crate) extern "C" fn atomic_access(
+    context: &RuntimeContext,
+    args: *const u32,
+    offset: u32,
+    op: u32,
+) -> u64 {
+    let memory = context.memory_region(op >> 8);
+    let size = 1 << (op & 3);
+    let op = AtomicOp::from_code((op & 0xff) >> 2);
+    // SAFETY: Compiled code passes the operands it has on its stack, the last one first
+    let args = unsafe { slice::from_raw_parts(args, op.operands()) };
+    let address = match effective_address(memory, args[op.operands() - 1], offset, size) {
+        Ok(address) => address,
+        Err(trap) => return trapping_result(Err(trap)),
+    };
+
+    // Operands are wrapped to the access size, like the stored value
+    let mask = u32::MAX >> (32 - size * 8);
+    let value = args[0] & mask;
+    let old = interrupt::free(|_| {
+        // SAFETY: The address was checked above
+        let old = unsafe { read(memory, address, size) };
+        let new = match op {
+            AtomicOp::Load => return old,
+            AtomicOp::Store => value,
+            AtomicOp::Add => old.wrapping_add(value),
+            AtomicOp::Sub => old.wrapping_sub(value),
+            AtomicOp::And => old & value,
+            AtomicOp::Or => old | value,
+            AtomicOp::Xor => old ^ value,
+            AtomicOp::Xchg => value,
+            AtomicOp::Cmpxchg if old == args[1] & mask => value,
+            AtomicOp::Cmpxchg => return old,
+        };
+
+        // SAFETY: As above
+        unsafe { write(memory, address, size, new & mask) };
+        old
+    });
+
+    trapping_result(Ok(old))
+}
+
+/// `memory.atomic.notify`, which leaves waking waiters to the host's hook
+pub(crate) extern "C" fn atomic_notify(
+    context: &RuntimeContext,
+    args: &[u32; 2],
+    offset: u32,
+    memory: u32,
+) -> u64 {
+    let [count, address] = *args;
+    let memory = context.memory_region(memory);
+    trapping_result(
+        effective_address(memory, address, offset, 4).map(|address| {
+            // SAFETY: The module outlives any code running in it
+            let module = unsafe { &mut *(context.module as *mut WasmModule) };
+            module.atomic_notify(address as u32, count)
+        }),
+    )
+}
+
+/// The access an atomic load, store or read-modify-write makes, with its size in bytes
+pub(crate) fn atomic_access_kind(op: &Operator) -> Option<(MemArg, AtomicOp, u32)> {
+    let access = match *op {
+        Operator::I32AtomicLoad { memarg } => (memarg, AtomicOp::Load, 4),
+        Operator::I32AtomicLoad8U { memarg } => (memarg, AtomicOp::Load, 1),
+        Operator::I32AtomicLoad16U { memarg } => (memarg, AtomicOp::Load, 2),
+        Operator::I32AtomicStore { memarg } => (memarg, AtomicOp::Store, 4),
+        Operator::I32AtomicStore8 { memarg } => (memarg, AtomicOp::Store, 1),
+        Operator::I32AtomicStore16 { memarg } => (memarg, AtomicOp::Store, 2),
+        Operator::I32AtomicRmwAdd { memarg } => (memarg, AtomicOp::Add, 4),
+        Operator::I32AtomicRmw8AddU { memarg } => (memarg, AtomicOp::Add, 1),
+        Operator::I32AtomicRmw16AddU { memarg } => (memarg, AtomicOp::Add, 2),
+        Operator::I32AtomicRmwSub { memarg } => (memarg, AtomicOp::Sub, 4),
+        Operator::I32AtomicRmw8SubU { memarg } => (memarg, AtomicOp::Sub, 1),
+        Operator::I32AtomicRmw16SubU { memarg } => (memarg, AtomicOp::Sub, 2),
+        Operator::I32AtomicRmwAnd { memarg } => (memarg, AtomicOp::And, 4),
+        Operator::I32AtomicRmw8AndU { memarg } => (memarg, AtomicOp::And, 1),
+        Operator::I32AtomicRmw16AndU { memarg } => (memarg, AtomicOp::And, 2),
+        Operator::I32AtomicRmwOr { memarg } => (memarg, AtomicOp::Or, 4),
+        Operator::I32AtomicRmw8OrU { memarg } => (memarg, AtomicOp::Or, 1),
+        Operator::I32AtomicRmw16OrU { memarg } => (memarg, AtomicOp::Or, 2),
+        Operator::I32AtomicRmwXor { memarg } => (memarg, AtomicOp::Xor, 4),
+        Operator::I32AtomicRmw8XorU { memarg } => (memarg, AtomicOp::Xor, 1),
+        Operator::I32AtomicRmw16XorU { memarg } => (memarg, AtomicOp::Xor, 2),
+        Operator::I32AtomicRmwXchg { memarg } => (memarg, AtomicOp::Xchg, 4),
+        Operator::I32AtomicRmw8XchgU { memarg } => (memarg, AtomicOp::Xchg, 1),
+        Operator::I32AtomicRmw16XchgU { memarg } => (memarg, AtomicOp::Xchg, 2),
+        Operator::I32AtomicRmwCmpxchg { memarg } => (memarg, AtomicOp::Cmpxchg, 4),
+        Operator::I32AtomicRmw8CmpxchgU { memarg } => (memarg, AtomicOp::Cmpxchg, 1),
+        Operator::I32AtomicRmw16CmpxchgU { memarg } => (memarg, AtomicOp::Cmpxchg, 2),
+        _ => return None,
+    };
+
+    Some(access)
+}
+
+/// Atomic loads, stores and read-modify-writes of `size` bytes
+///
+/// Everything but stores pushes the value that was in memory.
+pub(crate) fn atomic_access_op(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    memarg: &MemArg,
+    op: AtomicOp,
+    size: u32,
+    unwind: Label,
+) {
+    let code = memarg.memory << 8 | (op as u32) << 2 | size.trailing_zeros();
+    let operands = op.operands() as u8;
+    call_with_operands(
+        func,
+        data_map,
+        Helper::AtomicAccess,
+        operands,
+        &[memarg.offset as u32, code],
+    );
+    check_trap(func, B, unwind);
+
+    if !matches!(op, AtomicOp::Store) {
+        func.push(register_list!(A));
+    }
+}
+
+pub(crate) fn memory_atomic_notify(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    memarg: &MemArg,
+    unwind: Label,
+) {
+    call_with_operands(
+        func,
+        data_map,
+        Helper::AtomicNotify,
+        2,
+        &[memarg.offset as u32, memarg.memory],
+    );
+    check_trap(func, B, unwind);
+    func.push(register_list!(A));
+}
@@ -1,35 +1,151 @@
-#![allow(clippy::fn_to_numeric_cast_with_truncation)]
 use alloc::collections::BTreeMap;
-use pico_emit::{emitter::Label, instructions::*, register_list, Emitter};
+use pico_emit::{emitter::Label, instructions::*, Emitter};
 
 use crate::aliases::*;
+use crate::context::{DataSegment, Helper, MemoryRegion, RuntimeContext};
+use crate::wasm_module::Trap;
 
-use super::get_data_label;
+use super::{call_with_operands, check_trap, load_helper, load_index};
 
-extern "C" {
-    fn __aeabi_memcpy(dst: *mut u8, src: *const u8, n: usize);
-    fn __aeabi_memset(dst: *mut u8, n: usize, value: u8);
+// Checks that `len` bytes starting at `start` are inside the memory
+fn in_bounds(memory: &MemoryRegion, start: u32, len: u32) -> bool {
+    memory.contains(start as u64, len as u64)
 }
 
-pub(crate) fn memory_copy(func: &mut Emitter, data_map: &mut BTreeMap<u32, Label>) {
-    func.pop(register_list!(B, C, D));
-    // B now has n, C has src, D has dst (annoyingly)
-    // memcpy takes A dst, B src, C n
-    func.adds(A, Add2(MEMORY, D));
-    func.adds(D, Add2(MEMORY, C));
-    func.movs(C, B);
-    func.movs(B, D);
+/// `memory.copy` with memmove semantics, `args` points at its operands on the WASM stack
+///
+/// Returns a trap code, or 0 on success.
+pub(crate) extern "C" fn memory_copy_checked(
+    context: &RuntimeContext,
+    args: &[u32; 3],
+    dst_memory: u32,
+    src_memory: u32,
+) -> u32 {
+    let [len, src, dst] = *args; // The stack grows down, so the last operand comes first
+    let src_memory = context.memory_region(src_memory);
+    let dst_memory = context.memory_region(dst_memory);
+    if !in_bounds(src_memory, src, len) || !in_bounds(dst_memory, dst, len) {
+        return Trap::OutOfBoundsMemoryAccess as u32;
+    }
 
-    let memcpy = get_data_label(func, data_map, __aeabi_memcpy as u32);
-    func.ldr(D, memcpy);
-    func.blx(D);
+    // SAFETY: Both ranges were checked against their memories, `copy` allows them to overlap
+    unsafe {
+        core::ptr::copy(
+            (src_memory.base as *const u8).add(src as usize),
+            (dst_memory.base as *mut u8).add(dst as usize),
+            len as usize,
+        )
+    };
+    0
 }
 
-pub fn memory_fill(func: &mut Emitter, data_map: &mut BTreeMap<u32, Label>) {
-    func.pop(register_list!(B, C, D));
-    func.movs(D, A);
+/// `memory.fill`, `args` points at its operands on the WASM stack
+///
+/// Returns a trap code, or 0 on success.
+pub(crate) extern "C" fn memory_fill_checked(
+    context: &RuntimeContext,
+    args: &[u32; 3],
+    memory: u32,
+) -> u32 {
+    let [len, value, dst] = *args;
+    let memory = context.memory_region(memory);
+    if !in_bounds(memory, dst, len) {
+        return Trap::OutOfBoundsMemoryAccess as u32;
+    }
 
-    let memset = get_data_label(func, data_map, __aeabi_memset as u32);
-    func.ldr(D, memset);
-    func.blx(D);
+    let memory = memory.base as *mut u8;
+    // SAFETY: The range was checked against the memory
+    unsafe { core::ptr::write_bytes(memory.add(dst as usize), value as u8, len as usize) };
+    0
+}
+
+fn data_segment(context: &RuntimeContext, index: u32) -> *mut DataSegment {
+    assert!(
+        index < context.data_segment_count,
+        "Data segment {} does not exist",
+        index
+    );
+
+    // SAFETY: The index was checked against the table
+    unsafe { (context.data_segments as *mut DataSegment).add(index as usize) }
+}
+
+/// `memory.init`, `args` points at its operands on the WASM stack
+///
+/// Returns a trap code, or 0 on success.
+pub(crate) extern "C" fn memory_init_checked(
+    context: &RuntimeContext,
+    args: &[u32; 3],
+    segment: u32,
+    memory: u32,
+) -> u32 {
+    let [len, src, dst] = *args;
+    // SAFETY: The module keeps its segment table alive and in place while code runs
+    let segment = unsafe { *data_segment(context, segment) };
+    let memory = context.memory_region(memory);
+    let src_in_bounds = src.checked_add(len).is_some_and(|end| end <= segment.len);
+    if !src_in_bounds || !in_bounds(memory, dst, len) {
+        return Trap::OutOfBoundsMemoryAccess as u32;
+    }
+
+    let memory = memory.base as *mut u8;
+    // SAFETY: Both ranges were checked, and a segment never overlaps the memory
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            segment.data.add(src as usize),
+            memory.add(dst as usize),
+            len as usize,
+        )
+    };
+    0
+}
+
+pub(crate) extern "C" fn drop_data_segment(context: &RuntimeContext, segment: u32) {
+    // SAFETY: The module keeps its segment table alive and in place while code runs
+    unsafe { (*data_segment(context, segment)).len = 0 };
+}
+
+pub(crate) fn memory_copy(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    dst_memory: u32,
+    src_memory: u32,
+    unwind: Label,
+) {
+    call_with_operands(
+        func,
+        data_map,
+        Helper::MemoryCopy,
+        3,
+        &[dst_memory, src_memory],
+    );
+    check_trap(func, A, unwind);
+}
+
+pub(crate) fn memory_fill(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    memory: u32,
+    unwind: Label,
+) {
+    call_with_operands(func, data_map, Helper::MemoryFill, 3, &[memory]);
+    check_trap(func, A, unwind);
+}
+
+pub(crate) fn memory_init(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    data_index: u32,
+    memory: u32,
+    unwind: Label,
+) {
+    call_with_operands(func, data_map, Helper::MemoryInit, 3, &[data_index, memory]);
+    check_trap(func, A, unwind);
+}
+
+pub(crate) fn data_drop(func: &mut Emitter, data_map: &mut BTreeMap<u32, Label>, data_index: u32) {
+    func.movs(A, CONTEXT);
+    load_index(func, data_map, B, data_index);
+    load_helper(func, C, Helper::DataDrop);
+    func.blx(C);
 }
@@ -1,12 +1,23 @@
+use crate::context::{ContextField, Helper, RuntimeContext};
+use crate::wasm_module::Trap;
 use crate::{aliases::*, compiler::Scope};
+use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
+use core::slice;
 use pico_emit::{emitter::Label, instructions::*, register_list, registers::*, Emitter};
+use ux2::u5;
+
+use super::{
+    check_trap, exceptions::end_try, load_context_field, load_helper, load_index,
+    locals::add_offset, tables::table, trapping_result,
+};
 
 fn get_branch_target(depth: u32, scope_stack: &[Scope]) -> &Label {
     match &scope_stack[scope_stack.len() - 1 - depth as usize] {
         Scope::Block(label) => label,
         Scope::Loop(label) => label,
         Scope::If { end_label, .. } => end_label,
+        Scope::Try { end_label, .. } => end_label,
     }
 }
 
@@ -49,7 +60,7 @@ pub(crate) fn r#else(func: &mut Emitter, scope_stack: &mut Vec<Scope>) {
     scope_stack.push(Scope::Block(end_label)); // Now just becomes a normal block
 }
 
-pub(crate) fn end(func: &mut Emitter, scope_stack: &mut Vec<Scope>) {
+pub(crate) fn end(func: &mut Emitter, scope_stack: &mut Vec<Scope>, unwind: Label) {
     let scope = scope_stack.pop().expect("Scope stack should not be empty");
 
     match scope {
@@ -63,6 +74,7 @@ pub(crate) fn end(func: &mut Emitter, scope_stack: &mut Vec<Scope>) {
             func.label(&mut end_label);
         }
         Scope::Loop(_) => {} // Loops don't need an end label
+        scope @ Scope::Try { .. } => end_try(func, scope, scope_stack, unwind),
     };
 }
 
@@ -98,16 +110,121 @@ pub(crate) fn r#return(func: &mut Emitter, scope_stack: &[Scope]) {
     func.branch(*end);
 }
 
-pub(crate) fn call(func: &mut Emitter, call_func: &Label, function_index: u32) {
-    // Setup for call
-    func.mov(r0, MODULE); // First arg is &self
-    func.movs(r1, function_index as u8); // Second arg is function to call
+pub(crate) fn call(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    function_index: u32,
+    unwind: Label,
+) {
+    load_index(func, data_map, r1, function_index); // Second arg is function to call
+    call_function(func, unwind);
+}
+
+/// Finds the function called by `call_indirect` and checks that it has the expected type
+///
+/// Returns the function index in r0 and a trap code (or 0) in r1.
+pub(crate) extern "C" fn resolve_indirect_call(
+    context: &RuntimeContext,
+    table_index: u32,
+    type_index: u32,
+    element: u32,
+) -> u64 {
+    let table = match table(context, table_index) {
+        Ok(table) => table,
+        Err(trap) => return trapping_result(Err(trap)),
+    };
+
+    // SAFETY: The module keeps its type lists alive and in place while code runs
+    let (function_types, type_ids) = unsafe {
+        (
+            slice::from_raw_parts(
+                context.function_types as *const u32,
+                context.function_count as usize,
+            ),
+            slice::from_raw_parts(context.type_ids as *const u32, context.type_count as usize),
+        )
+    };
+
+    let Some(reference) = table.elements.get(element as usize) else {
+        return trapping_result(Err(Trap::UndefinedElement));
+    };
+
+    // Function references are stored as the index plus one, so null is 0
+    let Some(function) = reference.checked_sub(1) else {
+        return trapping_result(Err(Trap::UninitializedElement));
+    };
+
+    let expected = type_ids.get(type_index as usize);
+    if expected.is_none() || function_types.get(function as usize) != expected {
+        return trapping_result(Err(Trap::IndirectCallTypeMismatch));
+    }
+
+    trapping_result(Ok(function))
+}
+
+// Pops the element index and leaves the index of the function it refers to in r0
+pub(crate) fn resolve_indirect(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    type_index: u32,
+    table_index: u32,
+    unwind: Label,
+) {
+    // Every argument register is needed, so the address goes into the scratch register
+    load_helper(func, r0, Helper::ResolveIndirectCall);
+    func.mov(r12, r0);
+    func.pop(register_list!(r3)); // Fourth arg is the element index
+    func.movs(r0, CONTEXT);
+    load_index(func, data_map, r1, table_index);
+    load_index(func, data_map, r2, type_index);
+    func.blx(r12);
+    check_trap(func, r1, unwind);
+}
+
+pub(crate) fn call_indirect(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    type_index: u32,
+    table_index: u32,
+    unwind: Label,
+) {
+    resolve_indirect(func, data_map, type_index, table_index, unwind);
+    func.movs(r1, r0); // Second arg is function to call
+    call_function(func, unwind);
+}
+
+/// Replaces the locals with the callee's args and returns, leaving the call of the function whose
+/// index is in r0 to whoever called this function, so tail calls need no extra stack
+pub(crate) fn return_call(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    locals: usize,
+    params: usize,
+    func_return: Label,
+) {
+    func.adds(r0, 1); // Stored as the index plus one, so 0 means no tail call
+    func.str(
+        r0,
+        ImmOffset(CONTEXT, u5::new(ContextField::TailCall as u8)),
+    );
+    store_results(func, data_map, locals, params);
+    func.branch(func_return);
+}
+
+// Calls the function whose index is in r1 through the module
+fn call_function(func: &mut Emitter, unwind: Label) {
+    load_context_field(func, r0, ContextField::Module); // First arg is &self
     func.mov(r2, sp); // Third arg is the stack pointer
     func.mov(sp, ARCH_SP); // Restore sp since we're calling into native code
-    func.ldr(r3, *call_func); // Get address of call into r2
+    load_context_field(func, r3, ContextField::CallFunc); // Get address of call into r3
 
     func.blx(r3); // Call the function
     func.mov(sp, r0); // Load the new stack pointer
+
+    // The callee failed, leave without touching the stack so the host sees the error
+    load_context_field(func, r1, ContextField::Unwind);
+    func.cmp(r1, 0);
+    func.branch_if(Condition::NE, unwind);
 }
 
 pub(crate) fn select(func: &mut Emitter) {
@@ -120,3 +237,42 @@ pub(crate) fn select(func: &mut Emitter) {
     func.movs(A, B);
     func.push(register_list!(A));
 }
+
+/// Replaces the locals with the top `results` values of the stack, leaving `LOCALS` pointing at
+/// the last result so it becomes the caller's stack pointer
+///
+/// The first result ends up where the first param was, so the caller sees them in push order.
+pub(crate) fn store_results(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    locals: usize,
+    results: usize,
+) {
+    // The results can overlap their destination, which is never below them, so they are copied
+    // starting with the first one
+    let shift = (locals as i32 - results as i32) * 4;
+    add_offset(func, data_map, LOCALS, r0, shift);
+
+    if results <= 8 {
+        for index in (0..results as u8).rev() {
+            func.ldr(r1, SPWithOffset(index));
+            func.str(r1, ImmOffset(LOCALS, u5::new(index)));
+        }
+        return;
+    }
+
+    let size = results as i32 * 4;
+    func.mov(r2, sp);
+    add_offset(func, data_map, r2, r0, size);
+    func.mov(r3, LOCALS);
+    add_offset(func, data_map, r3, r0, size);
+
+    let mut copy_loop = func.create_label();
+    func.label(&mut copy_loop);
+    func.subs(r2, 4);
+    func.subs(r3, 4);
+    func.ldr(r1, r2);
+    func.str(r1, r3);
+    func.cmp(r3, LOCALS);
+    func.b_if(Condition::NE, copy_loop);
+}
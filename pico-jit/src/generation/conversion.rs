@@ -1,17 +1,68 @@
-#![allow(clippy::fn_to_numeric_cast_with_truncation)]
-use crate::{aliases::A, extern_func};
-use alloc::collections::BTreeMap;
+use crate::wasm_module::Trap;
+use crate::{aliases::*, context::Helper, extern_func};
 use pico_emit::{emitter::Label, instructions::*, register_list, Emitter};
 
+use super::{check_trap, load_helper, trapping_result};
+
 extern "C" {
-    fn __aeabi_f2iz(value: f32) -> i32;
-    fn __aeabi_i2f(value: i32) -> f32;
-    fn __aeabi_ui2f(value: u32) -> f32;
+    pub(crate) fn __aeabi_i2f(value: i32) -> f32;
+    pub(crate) fn __aeabi_ui2f(value: u32) -> f32;
+}
+
+// Bounds are exclusive and exactly representable, so comparing before truncating is enough
+pub(crate) extern "C" fn f32_to_i32(value: f32) -> u64 {
+    trapping_result(if value.is_nan() {
+        Err(Trap::InvalidConversionToInteger)
+    } else if (-2147483648.0..2147483648.0).contains(&value) {
+        Ok(value as i32 as u32)
+    } else {
+        Err(Trap::IntegerOverflow)
+    })
+}
+
+pub(crate) extern "C" fn f32_to_u32(value: f32) -> u64 {
+    trapping_result(if value.is_nan() {
+        Err(Trap::InvalidConversionToInteger)
+    } else if value > -1.0 && value < 4294967296.0 {
+        Ok(value as u32)
+    } else {
+        Err(Trap::IntegerOverflow)
+    })
+}
+
+// Rust's float to int casts already saturate and turn NaN into 0, as trunc_sat requires. There is
+// no FPU, so inline these would unpack the float by hand, several branches and a few dozen
+// instructions against the 4 of a call.
+pub(crate) extern "C" fn f32_to_i32_sat(value: f32) -> i32 {
+    value as i32
+}
+
+pub(crate) extern "C" fn f32_to_u32_sat(value: f32) -> u32 {
+    value as u32
+}
+
+fn trapping_conversion(func: &mut Emitter, helper: Helper, unwind: Label) {
+    func.pop(register_list!(A));
+    load_helper(func, C, helper);
+    func.blx(C);
+    check_trap(func, B, unwind);
+    func.push(register_list!(A));
+}
+
+pub fn i32_trunc_f32_s(func: &mut Emitter, unwind: Label) {
+    trapping_conversion(func, Helper::F32ToI32, unwind);
+}
+
+pub fn i32_trunc_f32_u(func: &mut Emitter, unwind: Label) {
+    trapping_conversion(func, Helper::F32ToU32, unwind);
+}
+
+pub fn i32_trunc_sat_f32_s(func: &mut Emitter) {
+    extern_func!(func, Helper::F32ToI32Sat, (A) -> A);
 }
 
-pub fn i32_trunc_f32_s(func: &mut Emitter, data_map: &mut BTreeMap<u32, Label>) {
-    // TODO: THIS GIVES THE INCORRECT VALUE FOR NEGATIVE NUMBERS (-1.5 SHOULD BE -1)
-    extern_func!(func, data_map, __aeabi_f2iz, (A) -> A);
+pub fn i32_trunc_sat_f32_u(func: &mut Emitter) {
+    extern_func!(func, Helper::F32ToU32Sat, (A) -> A);
 }
 
 pub fn i32_extend8_s(func: &mut Emitter) {
@@ -26,10 +77,10 @@ pub fn i32_extend16_s(func: &mut Emitter) {
     func.push(register_list!(A));
 }
 
-pub fn f32_convert_i32_s(func: &mut Emitter, data_map: &mut BTreeMap<u32, Label>) {
-    extern_func!(func, data_map, __aeabi_i2f, (A) -> A);
+pub fn f32_convert_i32_s(func: &mut Emitter) {
+    extern_func!(func, Helper::I2f, (A) -> A);
 }
 
-pub fn f32_convert_i32_u(func: &mut Emitter, data_map: &mut BTreeMap<u32, Label>) {
-    extern_func!(func, data_map, __aeabi_ui2f, (A) -> A);
+pub fn f32_convert_i32_u(func: &mut Emitter) {
+    extern_func!(func, Helper::Ui2f, (A) -> A);
 }
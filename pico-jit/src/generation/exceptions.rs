@@ -0,0 +1,325 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::vec::Vec;
+use core::slice;
+use pico_emit::{
+    emitter::Label,
+    instructions::*,
+    registers::{r12, sp},
+    Emitter,
+};
+use ux2::u7;
+use wasmparser_nostd::ValType;
+
+use crate::aliases::*;
+use crate::compiler::Scope;
+use crate::context::{Helper, RuntimeContext};
+use crate::wasm_module::{Exception, Result, WasmError, WasmModule};
+
+use super::locals::{add_offset, load_local, store_local};
+use super::{call_native, load_helper, load_index};
+
+// Tag passed to `catch_exception` by `catch_all`
+const ANY_TAG: u32 = u32::MAX;
+
+/// `throw`, `payload` points at the values on the WASM stack, the last one first
+pub(crate) extern "C" fn throw_exception(
+    context: &RuntimeContext,
+    tag: u32,
+    payload: *const u32,
+    len: u32,
+) {
+    // SAFETY: Compiled code passes the operands it has on its stack
+    let payload = unsafe { slice::from_raw_parts(payload, len as usize) };
+    let exception = Exception {
+        tag,
+        payload: payload.iter().rev().copied().collect(),
+    };
+
+    // SAFETY: The module outlives any code running in it
+    let module = unsafe { &mut *(context.module as *mut WasmModule) };
+    module.throw(exception);
+}
+
+/// Catches the exception being unwound if it has the given tag, writing its payload to `payload`
+///
+/// Returns a handle to the caught exception, or 0 if it doesn't match. `previous` is the handle
+/// the clause caught last time, which is freed now.
+pub(crate) extern "C" fn catch_exception(
+    context: &RuntimeContext,
+    tag: u32,
+    previous: u32,
+    payload: *mut u32,
+) -> u32 {
+    // SAFETY: The module outlives any code running in it
+    let module = unsafe { &mut *(context.module as *mut WasmModule) };
+    let Some(exception) = module.catch((tag != ANY_TAG).then_some(tag)) else {
+        return 0;
+    };
+
+    if previous != 0 {
+        // SAFETY: Handles are only created below and freed once
+        drop(unsafe { Box::from_raw(previous as *mut Exception) });
+    }
+
+    if tag != ANY_TAG {
+        // SAFETY: Compiled code made room for the tag's payload, which the exception matches
+        let dest = unsafe { slice::from_raw_parts_mut(payload, exception.payload.len()) };
+        for (dest, value) in dest.iter_mut().zip(exception.payload.iter().rev()) {
+            *dest = *value;
+        }
+    }
+
+    Box::into_raw(Box::new(exception)) as u32
+}
+
+pub(crate) extern "C" fn rethrow_exception(context: &RuntimeContext, handle: u32) {
+    // SAFETY: Compiled code only rethrows from inside a catch clause, which holds a handle
+    let exception = unsafe { (*(handle as *const Exception)).clone() };
+    // SAFETY: The module outlives any code running in it
+    let module = unsafe { &mut *(context.module as *mut WasmModule) };
+    module.throw(exception);
+}
+
+/// Frees the exceptions caught by a function that is about to return
+///
+/// `slots` points at its hidden try locals, each pair is a handle and a stack pointer.
+pub(crate) extern "C" fn drop_exceptions(slots: *mut [u32; 2], count: u32) {
+    // SAFETY: Compiled code passes the hidden locals of its own frame
+    let slots = unsafe { slice::from_raw_parts_mut(slots, count as usize) };
+    for [handle, _] in slots {
+        if *handle != 0 {
+            // SAFETY: Handles are created by `catch_exception` and zeroed once freed
+            drop(unsafe { Box::from_raw(*handle as *mut Exception) });
+            *handle = 0;
+        }
+    }
+}
+
+/// Where exceptions and traps raised in the current scope go: the innermost try block that isn't
+/// in a catch clause yet, or the function's unwind path
+pub(crate) fn current_handler(scope_stack: &[Scope], unwind: Label) -> Label {
+    scope_stack
+        .iter()
+        .rev()
+        .find_map(|scope| match scope {
+            Scope::Try {
+                handler,
+                catching: false,
+                ..
+            } => Some(*handler),
+            _ => None,
+        })
+        .unwrap_or(unwind)
+}
+
+/// `try`, which remembers the stack pointer below its params for the catch clauses
+///
+/// `slot` is the first of the two hidden locals the block gets, the second one holds the caught
+/// exception.
+pub(crate) fn r#try(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    scope_stack: &mut Vec<Scope>,
+    locals: &[ValType],
+    slot: u32,
+    params: usize,
+) -> Result<()> {
+    func.mov(A, sp);
+    add_offset(func, data_map, A, B, params as i32 * 4);
+    store_local(func, data_map, locals, slot, A)?;
+
+    scope_stack.push(Scope::Try {
+        end_label: func.create_label(),
+        handler: func.create_label(),
+        slot,
+        catching: false,
+        next_clause: None,
+    });
+
+    Ok(())
+}
+
+/// `catch` with a tag, or `catch_all` without one
+///
+/// Each clause tests the exception being unwound and moves on to the next clause if it doesn't
+/// match, the first clause is where the try block's handler lands.
+pub(crate) fn catch(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    scope_stack: &mut [Scope],
+    locals: &[ValType],
+    tag: Option<u32>,
+    payload: usize,
+) -> Result<()> {
+    let Some(Scope::Try {
+        end_label,
+        handler,
+        slot,
+        catching,
+        next_clause,
+    }) = scope_stack.last_mut()
+    else {
+        unreachable!("Catch should always be inside a try block");
+    };
+    let slot = *slot;
+
+    // The end of the try body or of the previous clause
+    func.branch(*end_label);
+    match next_clause.take() {
+        Some(mut next_clause) => func.label(&mut next_clause),
+        None => {
+            *catching = true;
+            func.label(handler);
+            load_local(func, data_map, locals, slot, A)?;
+            func.mov(sp, A);
+        }
+    }
+
+    let payload = u8::try_from(payload)
+        .ok()
+        .filter(|payload| *payload < 128)
+        .ok_or_else(|| WasmError::UnsupportedOp(format!("Tag with {} values", payload)))?;
+    if payload > 0 {
+        func.sub(sp, u7::new(payload)); // Room for the payload, the immediate is in words
+    }
+
+    load_local(func, data_map, locals, slot + 1, C)?;
+    load_index(func, data_map, B, tag.unwrap_or(ANY_TAG));
+    func.mov(D, sp);
+    // Every argument register is needed, so the address goes into the scratch register
+    load_helper(func, A, Helper::CatchException);
+    func.mov(r12, A);
+    func.movs(A, CONTEXT);
+    call_native(func, r12);
+
+    let next = func.create_label();
+    let mut matched = func.create_label();
+    func.cmp(A, 0);
+    func.b_if(Condition::NE, matched);
+    if payload > 0 {
+        func.add(sp, u7::new(payload));
+    }
+    func.branch(next);
+    func.label(&mut matched);
+    store_local(func, data_map, locals, slot + 1, A)?;
+
+    *next_clause = Some(next);
+    Ok(())
+}
+
+/// The end of a try block, exceptions none of its clauses caught go on to the enclosing handler
+pub(crate) fn end_try(func: &mut Emitter, scope: Scope, scope_stack: &[Scope], unwind: Label) {
+    let Scope::Try {
+        mut end_label,
+        mut handler,
+        catching,
+        next_clause,
+        ..
+    } = scope
+    else {
+        unreachable!("Only try blocks end here");
+    };
+
+    func.branch(end_label);
+    if !catching {
+        // A try block without any clauses
+        func.label(&mut handler);
+    }
+
+    if let Some(mut next_clause) = next_clause {
+        func.label(&mut next_clause);
+    }
+
+    func.branch(current_handler(scope_stack, unwind));
+    func.label(&mut end_label);
+}
+
+/// `delegate`, which passes exceptions from the try body on to the handler of an enclosing block
+pub(crate) fn delegate(
+    func: &mut Emitter,
+    scope_stack: &mut Vec<Scope>,
+    depth: u32,
+    unwind: Label,
+) {
+    let Some(Scope::Try {
+        mut end_label,
+        mut handler,
+        ..
+    }) = scope_stack.pop()
+    else {
+        unreachable!("Delegate should always end a try block");
+    };
+
+    // The depth is relative to the blocks around the try block
+    let target = scope_stack.len() - 1 - depth as usize;
+    func.branch(end_label);
+    func.label(&mut handler);
+    func.branch(current_handler(&scope_stack[..=target], unwind));
+    func.label(&mut end_label);
+}
+
+pub(crate) fn throw(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    tag: u32,
+    payload: usize,
+    unwind: Label,
+) {
+    // Every argument register is needed, so the address goes into the scratch register
+    load_helper(func, A, Helper::Throw);
+    func.mov(r12, A);
+    func.movs(A, CONTEXT);
+    load_index(func, data_map, B, tag);
+    func.mov(C, sp);
+    load_index(func, data_map, D, payload as u32);
+    call_native(func, r12);
+    func.branch(unwind); // The handler resets the stack, so the payload can stay on it
+}
+
+pub(crate) fn rethrow(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    scope_stack: &[Scope],
+    locals: &[ValType],
+    depth: u32,
+    unwind: Label,
+) -> Result<()> {
+    let Scope::Try {
+        slot,
+        catching: true,
+        ..
+    } = scope_stack[scope_stack.len() - 1 - depth as usize]
+    else {
+        return Err(WasmError::UnsupportedOp(format!(
+            "Rethrow {} does not target a catch clause",
+            depth
+        )));
+    };
+
+    load_local(func, data_map, locals, slot + 1, B)?;
+    func.movs(A, CONTEXT);
+    load_helper(func, C, Helper::Rethrow);
+    call_native(func, C);
+    func.branch(unwind);
+    Ok(())
+}
+
+/// Frees every exception the function's try blocks caught, before its frame goes away
+///
+/// The hidden try locals are the last locals, so they start right at `LOCALS`.
+pub(crate) fn drop_caught(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    try_blocks: u32,
+) {
+    if try_blocks == 0 {
+        return;
+    }
+
+    func.mov(A, LOCALS);
+    load_index(func, data_map, B, try_blocks);
+    load_helper(func, C, Helper::DropExceptions);
+    call_native(func, C);
+}
@@ -1,28 +1,124 @@
-#![allow(clippy::fn_to_numeric_cast_with_truncation)]
-use crate::{aliases::*, extern_func};
+use crate::{aliases::*, context::Helper, extern_func};
 use alloc::collections::BTreeMap;
-use pico_emit::{
-    emitter::Label,
-    instructions::*,
-    register_list,
-    registers::{r4, r5},
-    Emitter,
-};
-use ux2::u3;
+use pico_emit::{emitter::Label, instructions::*, register_list, registers::sp, Emitter};
+use ux2::{u3, u5};
 use wasmparser_nostd::Ieee32;
 
-use super::get_data_label;
+use super::{get_data_label, load_helper};
 
 extern "C" {
-    fn __aeabi_fcmpeq(a: f32, b: f32) -> u32;
-    fn __aeabi_fcmplt(a: f32, b: f32) -> u32;
-    fn __aeabi_fcmpgt(a: f32, b: f32) -> u32;
-    fn __aeabi_fcmple(a: f32, b: f32) -> u32;
-    fn __aeabi_fcmpge(a: f32, b: f32) -> u32;
-    fn __aeabi_fadd(a: f32, b: f32) -> f32;
-    fn __aeabi_fsub(a: f32, b: f32) -> f32;
-    fn __aeabi_fmul(a: f32, b: f32) -> f32;
-    fn __aeabi_fdiv(n: f32, d: f32) -> f32;
+    pub(crate) fn __aeabi_fadd(a: f32, b: f32) -> f32;
+    pub(crate) fn __aeabi_fsub(a: f32, b: f32) -> f32;
+    pub(crate) fn __aeabi_fmul(a: f32, b: f32) -> f32;
+    pub(crate) fn __aeabi_fdiv(n: f32, d: f32) -> f32;
+}
+
+const SIGN_MASK: u32 = 0x8000_0000;
+const QUIET_BIT: u32 = 0x0040_0000;
+
+// Bits returned by `f32_ordering`, unordered (either side is NaN) is 0
+const LESS: u8 = 1;
+const EQUAL: u8 = 2;
+const GREATER: u8 = 4;
+
+fn is_nan(bits: u32) -> bool {
+    bits & !SIGN_MASK > 0x7F80_0000
+}
+
+/// Compares two floats by their bits, so the result doesn't depend on the runtime library
+pub(crate) extern "C" fn f32_ordering(lhs: u32, rhs: u32) -> u32 {
+    if is_nan(lhs) || is_nan(rhs) {
+        return 0;
+    }
+
+    // Map sign and magnitude onto integers that order the same way, with both zeros equal
+    let key = |bits: u32| {
+        let magnitude = (bits & !SIGN_MASK) as i32;
+        if bits & SIGN_MASK != 0 {
+            -magnitude
+        } else {
+            magnitude
+        }
+    };
+
+    match key(lhs).cmp(&key(rhs)) {
+        core::cmp::Ordering::Less => LESS as u32,
+        core::cmp::Ordering::Equal => EQUAL as u32,
+        core::cmp::Ordering::Greater => GREATER as u32,
+    }
+}
+
+// NaN inputs give a NaN with the quiet bit set, as wasm requires of arithmetic NaNs
+fn nan_operand(lhs: u32, rhs: u32) -> u32 {
+    (if is_nan(lhs) { lhs } else { rhs }) | QUIET_BIT
+}
+
+/// `f32.min`, where -0.0 is less than +0.0
+pub(crate) extern "C" fn minf(lhs: u32, rhs: u32) -> u32 {
+    match f32_ordering(lhs, rhs) as u8 {
+        LESS => lhs,
+        GREATER => rhs,
+        EQUAL => lhs | rhs, // Only zeros can differ here, and the sign bit makes -0.0 win
+        _ => nan_operand(lhs, rhs),
+    }
+}
+
+/// `f32.max`, where +0.0 is greater than -0.0
+pub(crate) extern "C" fn maxf(lhs: u32, rhs: u32) -> u32 {
+    match f32_ordering(lhs, rhs) as u8 {
+        LESS => rhs,
+        GREATER => lhs,
+        EQUAL => lhs & rhs,
+        _ => nan_operand(lhs, rhs),
+    }
+}
+
+/// Rounds towards zero by clearing the fraction bits below the binary point
+pub(crate) extern "C" fn truncf(value: f32) -> f32 {
+    let bits = value.to_bits();
+    let exponent = ((bits >> 23) & 0xFF) as i32 - 127;
+
+    if exponent >= 23 {
+        value // Already integral, infinite or NaN
+    } else if exponent < 0 {
+        f32::from_bits(bits & SIGN_MASK) // |value| < 1, keep the sign of the zero
+    } else {
+        f32::from_bits(bits & !(0x007F_FFFF >> exponent))
+    }
+}
+
+pub(crate) extern "C" fn floorf(value: f32) -> f32 {
+    let truncated = truncf(value);
+    if value < 0.0 && truncated != value {
+        truncated - 1.0
+    } else {
+        truncated
+    }
+}
+
+pub(crate) extern "C" fn ceilf(value: f32) -> f32 {
+    let truncated = truncf(value);
+    if value > 0.0 && truncated != value {
+        truncated + 1.0
+    } else {
+        truncated
+    }
+}
+
+/// Rounds to the nearest integer, with ties going to the even one
+pub(crate) extern "C" fn nearestf(value: f32) -> f32 {
+    let truncated = truncf(value);
+    let fraction = f32::from_bits((value - truncated).to_bits() & !SIGN_MASK);
+    if !(fraction > 0.5 || (fraction == 0.5 && truncated as i32 & 1 != 0)) {
+        return truncated;
+    }
+
+    // Rounding away from zero, a zero result can't happen here so the sign is kept
+    if value < 0.0 {
+        truncated - 1.0
+    } else {
+        truncated + 1.0
+    }
 }
 
 pub(crate) fn f32_const(func: &mut Emitter, data_map: &mut BTreeMap<u32, Label>, value: Ieee32) {
@@ -32,105 +128,135 @@ pub(crate) fn f32_const(func: &mut Emitter, data_map: &mut BTreeMap<u32, Label>,
     func.push(register_list!(A));
 }
 
-fn f32_compare(
-    func: &mut Emitter,
-    data_map: &mut BTreeMap<u32, Label>,
-    compare_func: unsafe extern "C" fn(f32, f32) -> u32,
-) {
-    func.pop(register_list!(A, B));
-    let cmp_func = get_data_label(func, data_map, compare_func as u32);
-    func.ldr(C, cmp_func);
+// Leaves the `f32_ordering` of the two operands in A
+fn f32_order(func: &mut Emitter) {
+    func.pop(register_list!(B, C));
+    func.movs(A, C);
+    load_helper(func, C, Helper::Fordering);
     func.blx(C);
-    func.subs(C, Sub2Imm(A, u3::new(1)));
+}
+
+fn f32_compare(func: &mut Emitter, mask: u8) {
+    f32_order(func);
+    func.movs(B, mask);
+    func.and(A, B);
+    func.subs(C, Sub2Imm(A, u3::new(1))); // Any set bit becomes 1
     func.sbc(A, C);
     func.push(register_list!(A));
 }
 
-pub(crate) fn f32_eq(func: &mut Emitter, data_map: &mut BTreeMap<u32, Label>) {
-    f32_compare(func, data_map, __aeabi_fcmpeq);
+pub(crate) fn f32_eq(func: &mut Emitter) {
+    f32_compare(func, EQUAL);
 }
 
-pub(crate) fn f32_ne(func: &mut Emitter, data_map: &mut BTreeMap<u32, Label>) {
-    // Subs is different so we can't use the helper function
-    func.pop(register_list!(A, B));
-    let cmp_func = get_data_label(func, data_map, __aeabi_fcmpeq as u32);
-    func.ldr(C, cmp_func);
-    func.blx(C);
+pub(crate) fn f32_ne(func: &mut Emitter) {
+    // Unordered operands are not equal, so this is the inverse of eq
+    f32_order(func);
+    func.movs(B, EQUAL);
+    func.and(A, B);
     func.rsb(C, A);
     func.adc(A, C);
     func.push(register_list!(A));
 }
 
-pub(crate) fn f32_lt(func: &mut Emitter, data_map: &mut BTreeMap<u32, Label>) {
-    // We do the opposite of the comparison, because data from the stack is loaded in reverse order
-    f32_compare(func, data_map, __aeabi_fcmpgt);
+pub(crate) fn f32_lt(func: &mut Emitter) {
+    f32_compare(func, LESS);
 }
 
-pub(crate) fn f32_gt(func: &mut Emitter, data_map: &mut BTreeMap<u32, Label>) {
-    f32_compare(func, data_map, __aeabi_fcmplt);
+pub(crate) fn f32_gt(func: &mut Emitter) {
+    f32_compare(func, GREATER);
 }
 
-pub(crate) fn f32_le(func: &mut Emitter, data_map: &mut BTreeMap<u32, Label>) {
-    f32_compare(func, data_map, __aeabi_fcmpge);
+pub(crate) fn f32_le(func: &mut Emitter) {
+    f32_compare(func, LESS | EQUAL);
 }
 
-pub(crate) fn f32_ge(func: &mut Emitter, data_map: &mut BTreeMap<u32, Label>) {
-    f32_compare(func, data_map, __aeabi_fcmple);
+pub(crate) fn f32_ge(func: &mut Emitter) {
+    f32_compare(func, GREATER | EQUAL);
 }
 
-pub(crate) fn f32_sqrt(func: &mut Emitter, data_map: &mut BTreeMap<u32, Label>) {
-    use rp_pico::hal::rom_data::float_funcs::fsqrt;
-    extern_func!(func, data_map, fsqrt, (A) -> A)
+pub(crate) fn f32_abs(func: &mut Emitter) {
+    func.ldr(A, sp);
+    func.lsl(A, ImmShift(A, u5::new(1))); // Shift the sign bit out
+    func.lsr(A, ImmShift(A, u5::new(1)));
+    func.str(A, sp);
 }
 
-pub(crate) fn f32_add(func: &mut Emitter, data_map: &mut BTreeMap<u32, Label>) {
-    extern_func!(func, data_map, __aeabi_fadd, (A, B) -> A)
+pub(crate) fn f32_neg(func: &mut Emitter) {
+    func.ldr(A, sp);
+    func.movs(B, 1);
+    func.lsl(B, ImmShift(B, u5::new(31))); // Sign bit
+    func.eor(A, B);
+    func.str(A, sp);
 }
 
-pub(crate) fn f32_sub(func: &mut Emitter, data_map: &mut BTreeMap<u32, Label>) {
-    extern_func!(func, data_map, __aeabi_fsub, (A, B) -> A)
+pub(crate) fn f32_copysign(func: &mut Emitter) {
+    func.pop(register_list!(A, B)); // A is the sign source, B the magnitude
+    func.lsr(A, ImmShift(A, u5::new(31)));
+    func.lsl(A, ImmShift(A, u5::new(31))); // Keep only the sign bit
+    func.lsl(B, ImmShift(B, u5::new(1)));
+    func.lsr(B, ImmShift(B, u5::new(1))); // Clear the sign bit
+    func.or(A, B);
+    func.push(register_list!(A));
 }
 
-pub(crate) fn f32_mul(func: &mut Emitter, data_map: &mut BTreeMap<u32, Label>) {
-    extern_func!(func, data_map, __aeabi_fmul, (A, B) -> A)
+pub(crate) fn f32_ceil(func: &mut Emitter) {
+    extern_func!(func, Helper::Ceilf, (A) -> A)
 }
 
-pub(crate) fn f32_div(func: &mut Emitter, data_map: &mut BTreeMap<u32, Label>) {
-    extern_func!(func, data_map, __aeabi_fdiv, (A, B) -> A)
+pub(crate) fn f32_floor(func: &mut Emitter) {
+    extern_func!(func, Helper::Floorf, (A) -> A)
 }
 
-pub(crate) fn f32_min(func: &mut Emitter, data_map: &mut BTreeMap<u32, Label>) {
-    func.pop(register_list!(A, B));
-    func.push(register_list!(r4, r5)); // We need to save these registers under the ABI
-    func.movs(r5, A);
-    func.movs(r4, B);
-    let lt = get_data_label(func, data_map, __aeabi_fcmplt as u32);
-    func.ldr(C, lt);
-    func.blx(C);
-    func.cmp(A, 0);
-    let mut r#else = func.create_label();
-    func.b_if(Condition::EQ, r#else);
-    func.movs(r4, r5);
-    func.label(&mut r#else);
-    func.movs(A, r4);
-    func.pop(register_list!(r4, r5)); // Restore the registers
-    func.push(register_list!(A));
+pub(crate) fn f32_trunc(func: &mut Emitter) {
+    extern_func!(func, Helper::Truncf, (A) -> A)
 }
 
-pub(crate) fn f32_max(func: &mut Emitter, data_map: &mut BTreeMap<u32, Label>) {
-    func.pop(register_list!(A, B));
-    func.push(register_list!(r4, r5)); // We need to save these registers under the ABI
-    func.movs(r5, A);
-    func.movs(r4, B);
-    let gt = get_data_label(func, data_map, __aeabi_fcmpgt as u32);
-    func.ldr(C, gt);
-    func.blx(C);
-    func.cmp(A, 0);
-    let mut r#else = func.create_label();
-    func.b_if(Condition::EQ, r#else);
-    func.movs(r4, r5);
-    func.label(&mut r#else);
-    func.movs(A, r4);
-    func.pop(register_list!(r4, r5)); // Restore the registers
-    func.push(register_list!(A));
+pub(crate) fn f32_nearest(func: &mut Emitter) {
+    extern_func!(func, Helper::Nearestf, (A) -> A)
+}
+
+pub(crate) fn f32_sqrt(func: &mut Emitter) {
+    extern_func!(func, Helper::Fsqrt, (A) -> A)
+}
+
+pub(crate) fn f32_add(func: &mut Emitter) {
+    extern_func!(func, Helper::Fadd, (A, B) -> A)
+}
+
+pub(crate) fn f32_sub(func: &mut Emitter) {
+    extern_func!(func, Helper::Fsub, (A, B) -> A)
+}
+
+pub(crate) fn f32_mul(func: &mut Emitter) {
+    extern_func!(func, Helper::Fmul, (A, B) -> A)
+}
+
+pub(crate) fn f32_div(func: &mut Emitter) {
+    extern_func!(func, Helper::Fdiv, (A, B) -> A)
+}
+
+pub(crate) fn f32_min(func: &mut Emitter) {
+    extern_func!(func, Helper::Fmin, (A, B) -> A)
+}
+
+pub(crate) fn f32_max(func: &mut Emitter) {
+    extern_func!(func, Helper::Fmax, (A, B) -> A)
+}
+
+/// Replaces a NaN on top of the stack with the canonical NaN
+pub(crate) fn canonicalize_nan(func: &mut Emitter) {
+    let mut done = func.create_label();
+    func.ldr(A, sp);
+    func.lsl(B, ImmShift(A, u5::new(1))); // Drop the sign
+    func.movs(C, 0xFF);
+    func.lsl(C, ImmShift(C, u5::new(24))); // Infinity shifted left by one
+    func.cmp(B, C);
+    func.b_if(Condition::LS, done); // Not a NaN
+    func.movs(A, 0xFF);
+    func.lsl(A, ImmShift(A, u5::new(1)));
+    func.adds(A, 1);
+    func.lsl(A, ImmShift(A, u5::new(22))); // 0x7FC00000
+    func.str(A, sp);
+    func.label(&mut done);
 }
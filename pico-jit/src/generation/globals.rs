@@ -1,14 +1,80 @@
-use pico_emit::{instructions::*, register_list, Emitter};
+use alloc::collections::BTreeMap;
+use alloc::string::ToString;
+use pico_emit::{emitter::Label, instructions::*, register_list, Emitter};
 use ux2::u5;
+use wasmparser_nostd::GlobalType;
 
 use crate::aliases::*;
+use crate::wasm_module::{Result, WasmError};
+
+use super::get_data_label;
+
+enum GlobalOffset {
+    Immediate(u5),
+    Register,
+}
+
+// Puts the byte offset of a global into B when it is out of reach of an immediate offset
+fn global_offset(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    index: u32,
+) -> GlobalOffset {
+    if index < 32 {
+        return GlobalOffset::Immediate(u5::new(index as u8));
+    }
+
+    match u8::try_from(index) {
+        Ok(index) => {
+            func.movs(B, index);
+            func.lsl(B, ImmShift(B, u5::new(2)));
+        }
+        Err(_) => {
+            let offset = get_data_label(func, data_map, index * 4);
+            func.ldr(B, offset);
+        }
+    }
+
+    GlobalOffset::Register
+}
+
+fn global_type(globals: &[GlobalType], index: u32) -> Result<&GlobalType> {
+    globals
+        .get(index as usize)
+        .ok_or_else(|| WasmError::GlobalNotFound(index.to_string()))
+}
+
+pub fn global_get(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    globals: &[GlobalType],
+    index: u32,
+) -> Result<()> {
+    global_type(globals, index)?;
+    match global_offset(func, data_map, index) {
+        GlobalOffset::Immediate(offset) => func.ldr(A, ImmOffset(GLOBALS, offset)),
+        GlobalOffset::Register => func.ldr(A, RegOffset(GLOBALS, B)),
+    }
 
-pub fn global_get(func: &mut Emitter, index: u32) {
-    func.ldr(A, ImmOffset(GLOBALS, u5::new(index as u8)));
     func.push(register_list!(A));
+    Ok(())
 }
 
-pub fn global_set(func: &mut Emitter, index: u32) {
+pub fn global_set(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    globals: &[GlobalType],
+    index: u32,
+) -> Result<()> {
+    if !global_type(globals, index)?.mutable {
+        return Err(WasmError::ImmutableGlobal(index));
+    }
+
     func.pop(register_list!(A));
-    func.str(A, ImmOffset(GLOBALS, u5::new(index as u8)));
+    match global_offset(func, data_map, index) {
+        GlobalOffset::Immediate(offset) => func.str(A, ImmOffset(GLOBALS, offset)),
+        GlobalOffset::Register => func.str(A, RegOffset(GLOBALS, B)),
+    }
+
+    Ok(())
 }
@@ -1,16 +1,32 @@
-#![allow(clippy::fn_to_numeric_cast_with_truncation)]
-use crate::{aliases::*, extern_func};
+use crate::wasm_module::Trap;
+use crate::{aliases::*, context::Helper, extern_func};
 use alloc::collections::BTreeMap;
 use pico_emit::{emitter::Label, instructions::*, register_list, registers::*, Emitter};
 use ux2::{u3, u5};
 
-use super::get_data_label;
+use super::{get_data_label, load_helper, raise_trap};
 
 extern "C" {
-    fn __aeabi_idiv(n: i32, d: i32) -> i32;
-    fn __aeabi_uidiv(n: u32, d: u32) -> u32;
-    fn __aeabi_idivmod(n: i32, d: i32); // (r0: quotient, r1: remainder)
-    fn __aeabi_uidivmod(n: u32, d: u32); // (r0: quotient, r1: remainder)
+    pub(crate) fn __aeabi_idiv(n: i32, d: i32) -> i32;
+    pub(crate) fn __aeabi_uidiv(n: u32, d: u32) -> u32;
+    pub(crate) fn __aeabi_idivmod(n: i32, d: i32); // (r0: quotient, r1: remainder)
+    pub(crate) fn __aeabi_uidivmod(n: u32, d: u32); // (r0: quotient, r1: remainder)
+}
+
+// ARMv6-M has no bit counting instructions, so these are left to the compiler's runtime library.
+// Inline, popcnt alone takes 17 instructions and four literal pool constants, with clz and ctz
+// built on top of it, where a call takes 4. Code size is what the code budget limits, so unlike
+// sign extension, which is a single sxtb or sxth, these stay calls.
+pub(crate) extern "C" fn count_leading_zeros(value: u32) -> u32 {
+    value.leading_zeros()
+}
+
+pub(crate) extern "C" fn count_trailing_zeros(value: u32) -> u32 {
+    value.trailing_zeros()
+}
+
+pub(crate) extern "C" fn count_ones(value: u32) -> u32 {
+    value.count_ones()
 }
 
 pub(crate) fn i32_const(func: &mut Emitter, data_map: &mut BTreeMap<u32, Label>, value: i32) {
@@ -126,6 +142,18 @@ pub(crate) fn i32_ge_u(func: &mut Emitter) {
     func.push(register_list!(A));
 }
 
+pub(crate) fn i32_clz(func: &mut Emitter) {
+    extern_func!(func, Helper::Clz, (A) -> A);
+}
+
+pub(crate) fn i32_ctz(func: &mut Emitter) {
+    extern_func!(func, Helper::Ctz, (A) -> A);
+}
+
+pub(crate) fn i32_popcnt(func: &mut Emitter) {
+    extern_func!(func, Helper::Popcnt, (A) -> A);
+}
+
 pub(crate) fn i32_add(func: &mut Emitter) {
     func.pop(register_list!(A, B));
     func.adds(A, B);
@@ -144,20 +172,60 @@ pub(crate) fn i32_mul(func: &mut Emitter) {
     func.push(register_list!(A));
 }
 
-pub(crate) fn i32_div_s(func: &mut Emitter, data_map: &mut BTreeMap<u32, Label>) {
-    extern_func!(func, data_map, __aeabi_idiv, (A, B) -> A);
+// Pops the dividend into A and the divisor into B, trapping if the divisor is zero
+fn pop_division_operands(func: &mut Emitter, unwind: Label) {
+    func.pop(register_list!(B, C));
+    func.movs(A, C);
+    let mut nonzero = func.create_label();
+    func.cmp(B, 0);
+    func.b_if(Condition::NE, nonzero);
+    raise_trap(func, Trap::IntegerDivideByZero, unwind);
+    func.label(&mut nonzero);
+}
+
+pub(crate) fn i32_div_s(func: &mut Emitter, unwind: Label) {
+    pop_division_operands(func, unwind);
+    let mut divide = func.create_label();
+    func.movs(D, B);
+    func.adds(D, 1); // Zero only if the divisor is -1
+    func.b_if(Condition::NE, divide);
+    func.subs(D, Sub2Imm(A, u3::new(1))); // Only INT_MIN - 1 overflows
+    func.b_if(Condition::VC, divide);
+    raise_trap(func, Trap::IntegerOverflow, unwind);
+    func.label(&mut divide);
+    load_helper(func, C, Helper::Idiv);
+    func.blx(C);
+    func.push(register_list!(A));
 }
 
-pub(crate) fn i32_div_u(func: &mut Emitter, data_map: &mut BTreeMap<u32, Label>) {
-    extern_func!(func, data_map, __aeabi_uidiv, (A, B) -> A);
+pub(crate) fn i32_div_u(func: &mut Emitter, unwind: Label) {
+    pop_division_operands(func, unwind);
+    load_helper(func, C, Helper::Uidiv);
+    func.blx(C);
+    func.push(register_list!(A));
 }
 
-pub(crate) fn i32_rem_s(func: &mut Emitter, data_map: &mut BTreeMap<u32, Label>) {
-    extern_func!(func, data_map, __aeabi_idivmod, (A, B) -> B);
+pub(crate) fn i32_rem_s(func: &mut Emitter, unwind: Label) {
+    pop_division_operands(func, unwind);
+    let mut divide = func.create_label();
+    let mut end = func.create_label();
+    func.movs(D, B);
+    func.adds(D, 1); // Zero only if the divisor is -1
+    func.b_if(Condition::NE, divide);
+    func.movs(B, 0); // Anything % -1 is 0, and INT_MIN % -1 must not reach the helper
+    func.b(end);
+    func.label(&mut divide);
+    load_helper(func, C, Helper::Idivmod);
+    func.blx(C);
+    func.label(&mut end);
+    func.push(register_list!(B));
 }
 
-pub(crate) fn i32_rem_u(func: &mut Emitter, data_map: &mut BTreeMap<u32, Label>) {
-    extern_func!(func, data_map, __aeabi_uidivmod, (A, B) -> B);
+pub(crate) fn i32_rem_u(func: &mut Emitter, unwind: Label) {
+    pop_division_operands(func, unwind);
+    load_helper(func, C, Helper::Uidivmod);
+    func.blx(C);
+    func.push(register_list!(B));
 }
 
 pub(crate) fn i32_and(func: &mut Emitter) {
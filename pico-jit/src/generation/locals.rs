@@ -1,62 +1,124 @@
-use crate::wasm_module::Result;
-use crate::{aliases::*, wasm_module::WasmError};
-use pico_emit::{instructions::*, register_list, registers::*, Emitter};
+use alloc::collections::BTreeMap;
+use pico_emit::{emitter::Label, instructions::*, register_list, registers::*, Emitter};
 use ux2::u5;
 use wasmparser_nostd::ValType;
 
+use crate::aliases::*;
+use crate::wasm_module::{Result, WasmError};
+
+use super::get_data_label;
+
 pub enum LocalOffset {
     Immediate(u5),
-    Register(u8),
+    Register,
 }
 
-fn get_local_offset(local_index: u32, locals: &[ValType]) -> Result<LocalOffset> {
-    // TODO: Support up to 255 locals thru movs and reg offset
-    if let Ok(offset) = u5::try_from(locals.len() as u8 - local_index as u8 - 1) {
-        return Ok(LocalOffset::Immediate(offset));
-    } else {
-        let offset = u8::try_from(locals.len() as u8 - local_index as u8 - 1)
-            .map_err(|_| WasmError::TooManyLocals(locals.len() as u32))?;
+// Locals are stored in reverse order above LOCALS, so the first local has the highest address.
+// Offsets out of reach of an immediate are put into B, in bytes.
+fn get_local_offset(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    local_index: u32,
+    locals: &[ValType],
+) -> Result<LocalOffset> {
+    let offset = (locals.len() as u32)
+        .checked_sub(local_index + 1)
+        .ok_or(WasmError::InvalidLocal(local_index))?;
+    if offset < 32 {
+        return Ok(LocalOffset::Immediate(u5::new(offset as u8)));
+    }
 
-        Ok(LocalOffset::Register(offset))
+    match u8::try_from(offset * 4) {
+        Ok(offset) => func.movs(B, offset),
+        Err(_) => {
+            let offset = get_data_label(func, data_map, offset * 4);
+            func.ldr(B, offset);
+        }
     }
+
+    Ok(LocalOffset::Register)
 }
 
-pub(crate) fn local_get(func: &mut Emitter, locals: &[ValType], index: u32) -> Result<()> {
-    match get_local_offset(index, locals)? {
-        LocalOffset::Immediate(offset) => func.ldr(A, ImmOffset(LOCALS, offset)),
-        LocalOffset::Register(offset) => {
-            func.movs(B, offset);
-            func.lsl(B, ImmShift(B, u5::new(2)));
-            func.ldr(A, RegOffset(LOCALS, B));
-        }
+/// Loads a local into `reg`, which may be B
+pub(crate) fn load_local(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    locals: &[ValType],
+    index: u32,
+    reg: types::LowRegister,
+) -> Result<()> {
+    match get_local_offset(func, data_map, index, locals)? {
+        LocalOffset::Immediate(offset) => func.ldr(reg, ImmOffset(LOCALS, offset)),
+        LocalOffset::Register => func.ldr(reg, RegOffset(LOCALS, B)),
     }
 
-    func.push(register_list!(A));
     Ok(())
 }
 
-pub(crate) fn local_set(func: &mut Emitter, locals: &[ValType], index: u32) -> Result<()> {
-    func.pop(register_list!(A));
-    match get_local_offset(index, locals)? {
-        LocalOffset::Immediate(offset) => func.str(A, ImmOffset(LOCALS, offset)),
-        LocalOffset::Register(offset) => {
-            func.movs(B, offset);
-            func.lsl(B, ImmShift(B, u5::new(2)));
-            func.str(A, RegOffset(LOCALS, B));
-        }
+/// Stores `reg` into a local, B is used for the offset so it can't hold the value
+pub(crate) fn store_local(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    locals: &[ValType],
+    index: u32,
+    reg: types::LowRegister,
+) -> Result<()> {
+    match get_local_offset(func, data_map, index, locals)? {
+        LocalOffset::Immediate(offset) => func.str(reg, ImmOffset(LOCALS, offset)),
+        LocalOffset::Register => func.str(reg, RegOffset(LOCALS, B)),
     }
+
     Ok(())
 }
 
-pub(crate) fn local_tee(func: &mut Emitter, locals: &[ValType], index: u32) -> Result<()> {
+pub(crate) fn local_get(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    locals: &[ValType],
+    index: u32,
+) -> Result<()> {
+    load_local(func, data_map, locals, index, A)?;
+    func.push(register_list!(A));
+    Ok(())
+}
+
+pub(crate) fn local_set(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    locals: &[ValType],
+    index: u32,
+) -> Result<()> {
+    func.pop(register_list!(A));
+    store_local(func, data_map, locals, index, A)
+}
+
+pub(crate) fn local_tee(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    locals: &[ValType],
+    index: u32,
+) -> Result<()> {
     func.ldr(A, sp);
-    match get_local_offset(index, locals)? {
-        LocalOffset::Immediate(offset) => func.str(A, ImmOffset(LOCALS, offset)),
-        LocalOffset::Register(offset) => {
-            func.movs(B, offset);
-            func.lsl(B, ImmShift(B, u5::new(2)));
-            func.str(A, RegOffset(LOCALS, B));
+    store_local(func, data_map, locals, index, A)
+}
+
+/// Adds a constant number of bytes to `reg`, using `scratch` when it doesn't fit an immediate
+pub(crate) fn add_offset(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    reg: types::LowRegister,
+    scratch: types::LowRegister,
+    bytes: i32,
+) {
+    match bytes {
+        0 => {}
+        1..=255 => func.adds(reg, bytes as u8),
+        -255..=-1 => func.subs(reg, -bytes as u8),
+        _ => {
+            // Wrapping addition takes care of negative offsets
+            let bytes = get_data_label(func, data_map, bytes as u32);
+            func.ldr(scratch, bytes);
+            func.adds(reg, scratch);
         }
     }
-    Ok(())
 }
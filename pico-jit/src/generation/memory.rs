@@ -6,10 +6,11 @@ use ux2::u5;
 use wasmparser_nostd::MemArg;
 
 use crate::aliases::*;
+use crate::context::{ContextField, Helper, MemoryRegion};
 
-use super::get_data_label;
+use super::{get_data_label, load_context_field, load_helper, load_index};
 
-fn load32_unaligned(memory: *const u8, src: usize) -> u32 {
+pub(crate) fn load32_unaligned(memory: *const u8, src: usize) -> u32 {
     // if src > 65536 * 2 {
     //     panic!(
     //         "Attempted to read offset {} which is larger than 2 WASM pages",
@@ -34,7 +35,7 @@ fn load32_unaligned(memory: *const u8, src: usize) -> u32 {
     }
 }
 
-fn store32_unaligned(memory: *mut u8, dest: usize, value: u32) {
+pub(crate) fn store32_unaligned(memory: *mut u8, dest: usize, value: u32) {
     // if dest > 65536 * 2 {
     //     panic!(
     //         "Attempted to write to offset {} which is larger than 2 WASM pages",
@@ -59,6 +60,77 @@ fn store32_unaligned(memory: *mut u8, dest: usize, value: u32) {
     }
 }
 
+// Loads a word of the `MemoryRegion` of `memory` into `dest`, using `scratch` if it is too far
+// into the table for an immediate offset
+fn load_region_word(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    memory: u32,
+    word: u32,
+    dest: LowRegister,
+    scratch: LowRegister,
+) {
+    load_context_field(func, dest, ContextField::Memories);
+    let offset = memory * MemoryRegion::SIZE + word * 4;
+    let words = u8::try_from(offset / 4)
+        .ok()
+        .and_then(|words| u5::try_from(words).ok());
+    match words {
+        Some(words) => func.ldr(dest, ImmOffset(dest, words)),
+        None => {
+            load_index(func, data_map, scratch, offset);
+            func.ldr(dest, RegOffset(dest, scratch));
+        }
+    }
+}
+
+/// The register holding the base address of `memory`
+///
+/// Memory 0 stays in `MEMORY`, any other memory is looked up in the context and loaded into
+/// `dest`, which may clobber `scratch`.
+pub(crate) fn memory_base(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    memory: u32,
+    dest: LowRegister,
+    scratch: LowRegister,
+) -> LowRegister {
+    if memory == 0 {
+        return MEMORY;
+    }
+
+    load_region_word(func, data_map, memory, 0, dest, scratch);
+    dest
+}
+
+/// `memory.size`, memory 0 keeps its size in the context itself
+pub(crate) fn memory_size(func: &mut Emitter, data_map: &mut BTreeMap<u32, Label>, memory: u32) {
+    if memory == 0 {
+        load_context_field(func, A, ContextField::MemorySize);
+    } else {
+        load_region_word(func, data_map, memory, 2, A, B);
+    }
+    func.push(register_list!(A));
+}
+
+/// Memories never grow, so growing by anything but 0 pages fails and gives -1
+pub(crate) fn memory_grow(func: &mut Emitter, data_map: &mut BTreeMap<u32, Label>, memory: u32) {
+    func.pop(register_list!(C));
+    if memory == 0 {
+        load_context_field(func, A, ContextField::MemorySize);
+    } else {
+        load_region_word(func, data_map, memory, 2, A, B);
+    }
+
+    let mut done = func.create_label();
+    func.cmp(C, 0);
+    func.b_if(Condition::EQ, done);
+    func.movs(A, 0);
+    func.subs(A, 1);
+    func.label(&mut done);
+    func.push(register_list!(A));
+}
+
 fn load_memory_offset(
     func: &mut Emitter,
     memarg: &MemArg,
@@ -84,9 +156,13 @@ fn load_memory_offset(
 pub fn x32_load(func: &mut Emitter, memarg: &MemArg, data_map: &mut BTreeMap<u32, Label>) {
     func.pop(register_list!(B));
     load_memory_offset(func, memarg, data_map, B);
-    func.mov(A, MEMORY);
-    let load_func = get_data_label(func, data_map, load32_unaligned as u32);
-    func.ldr(C, load_func);
+    match memarg.memory {
+        0 => func.mov(A, MEMORY),
+        memory => {
+            memory_base(func, data_map, memory, A, C);
+        }
+    }
+    load_helper(func, C, Helper::Load32Unaligned);
     func.blx(C);
     func.push(register_list!(A));
 }
@@ -95,60 +171,88 @@ pub fn x32_store(func: &mut Emitter, memarg: &MemArg, data_map: &mut BTreeMap<u3
     func.pop(register_list!(A, B));
     load_memory_offset(func, memarg, data_map, B);
     func.mov(C, A);
-    func.mov(A, MEMORY);
-    let store_func = get_data_label(func, data_map, store32_unaligned as u32);
-    func.ldr(D, store_func);
+    match memarg.memory {
+        0 => func.mov(A, MEMORY),
+        memory => {
+            memory_base(func, data_map, memory, A, D);
+        }
+    }
+    load_helper(func, D, Helper::Store32Unaligned);
     func.blx(D);
 }
 
 pub fn i32_load8_u(func: &mut Emitter, memarg: &MemArg, data_map: &mut BTreeMap<u32, Label>) {
     func.pop(register_list!(A));
-    load_memory_offset(func, memarg, data_map, B);
-    func.ldrb(A, RegOffset(MEMORY, B));
+    load_memory_offset(func, memarg, data_map, A);
+    let base = memory_base(func, data_map, memarg.memory, B, C);
+    func.ldrb(A, RegOffset(base, A));
     func.push(register_list!(A));
 }
 
-pub fn i32_load16_u(func: &mut Emitter, memarg: &MemArg, data_map: &mut BTreeMap<u32, Label>) {
+pub fn i32_load8_s(func: &mut Emitter, memarg: &MemArg, data_map: &mut BTreeMap<u32, Label>) {
+    func.pop(register_list!(A));
+    load_memory_offset(func, memarg, data_map, A);
+    let base = memory_base(func, data_map, memarg.memory, B, C);
+    func.ldrsb(A, RegOffset(base, A));
+    func.push(register_list!(A));
+}
+
+// Loads the zero-extended halfword into A
+fn load16(func: &mut Emitter, memarg: &MemArg, data_map: &mut BTreeMap<u32, Label>) {
     func.pop(register_list!(A));
     load_memory_offset(func, memarg, data_map, A);
+    let base = memory_base(func, data_map, memarg.memory, B, C);
     func.movs(C, 1);
     func.tst(C, A);
     let mut r#else = func.create_label();
     let mut end = func.create_label();
+    func.b_if(Condition::NE, r#else);
     func.bic(A, C);
-    func.ldrh(A, RegOffset(MEMORY, A));
+    func.ldrh(A, RegOffset(base, A));
     func.b(end);
     func.label(&mut r#else);
-    func.adds(C, Add2(MEMORY, A));
+    func.adds(C, Add2(base, A));
     func.ldrb(C, ImmOffset(C, u5::new(1)));
-    func.ldrb(A, RegOffset(MEMORY, A));
+    func.ldrb(A, RegOffset(base, A));
     func.lsl(C, ImmShift(C, u5::new(8)));
     func.or(A, C);
     func.label(&mut end);
+}
+
+pub fn i32_load16_u(func: &mut Emitter, memarg: &MemArg, data_map: &mut BTreeMap<u32, Label>) {
+    load16(func, memarg, data_map);
+    func.push(register_list!(A));
+}
+
+pub fn i32_load16_s(func: &mut Emitter, memarg: &MemArg, data_map: &mut BTreeMap<u32, Label>) {
+    load16(func, memarg, data_map);
+    func.sxth(A, A);
     func.push(register_list!(A));
 }
 
 pub fn i32_store8(func: &mut Emitter, memarg: &MemArg, data_map: &mut BTreeMap<u32, Label>) {
     func.pop(register_list!(A, B));
     load_memory_offset(func, memarg, data_map, B);
-    func.strb(A, RegOffset(MEMORY, B));
+    let base = memory_base(func, data_map, memarg.memory, C, D);
+    func.strb(A, RegOffset(base, B));
 }
 
 pub fn i32_store16(func: &mut Emitter, memarg: &MemArg, data_map: &mut BTreeMap<u32, Label>) {
     func.pop(register_list!(A, B));
     load_memory_offset(func, memarg, data_map, B);
+    let base = memory_base(func, data_map, memarg.memory, D, C);
     func.movs(C, 1);
     func.tst(C, B);
     let mut r#else = func.create_label();
     let mut end = func.create_label();
     func.b_if(Condition::NE, r#else);
     func.bic(B, C);
-    func.strh(A, RegOffset(MEMORY, B));
+    func.strh(A, RegOffset(base, B));
     func.b(end);
     func.label(&mut r#else);
-    func.strb(A, RegOffset(MEMORY, B));
+    func.strb(A, RegOffset(base, B));
     func.adds(B, 1);
     func.lsr(A, ImmShift(A, u5::new(8)));
-    func.strb(A, RegOffset(MEMORY, B));
+    func.strb(A, RegOffset(base, B));
     func.label(&mut end);
 }
@@ -1,14 +1,28 @@
+use crate::aliases::{A, ARCH_SP, B, C, CONTEXT, D, WASM_SP};
+use crate::context::{ContextField, Helper};
+use crate::wasm_module::Trap;
 use alloc::collections::BTreeMap;
-use pico_emit::{emitter::Label, Emitter};
+use pico_emit::{
+    emitter::Label,
+    instructions::*,
+    registers::traits::GeneralPurposeRegister,
+    registers::types::LowRegister,
+    registers::{r1, r12, sp},
+    ux2::{u5, u7},
+    Emitter,
+};
 
+pub mod atomics;
 pub mod bulk_memory;
 pub mod control_flow;
 pub mod conversion;
+pub mod exceptions;
 pub mod f32_ops;
 pub mod globals;
 pub mod i32_ops;
 pub mod locals;
 pub mod memory;
+pub mod tables;
 
 pub(crate) fn get_data_label(
     func: &mut Emitter,
@@ -18,32 +32,132 @@ pub(crate) fn get_data_label(
     *data_map.entry(value).or_insert_with(|| func.data(value))
 }
 
-// extern_func!(emitter, data_map, extern_func, (ARGS) -> Return reg)
+/// Loads an index that is known at compile time, e.g. of a segment or table, into `dest`
+pub(crate) fn load_index(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    dest: LowRegister,
+    index: u32,
+) {
+    match u8::try_from(index) {
+        Ok(index) => func.movs(dest, index),
+        Err(_) => {
+            let index = get_data_label(func, data_map, index);
+            func.ldr(dest, index);
+        }
+    }
+}
+
+/// Loads a word from the runtime context into `dest`
+pub(crate) fn load_context_field(func: &mut Emitter, dest: LowRegister, field: ContextField) {
+    func.ldr(dest, ImmOffset(CONTEXT, u5::new(field as u8)));
+}
+
+/// Loads the address of a runtime helper into `dest`, ready for a `blx`
+pub(crate) fn load_helper(func: &mut Emitter, dest: LowRegister, helper: Helper) {
+    let offset = helper.context_offset();
+    match u5::try_from(offset as u8) {
+        Ok(offset) => func.ldr(dest, ImmOffset(CONTEXT, offset)),
+        Err(_) => {
+            // Past the reach of an immediate offset, so go through a register instead
+            match u8::try_from(offset * 4) {
+                Ok(offset) => func.movs(dest, offset),
+                Err(_) => {
+                    let offset = u8::try_from(offset).expect("Helper table too large");
+                    func.movs(dest, offset);
+                    func.lsl(dest, ImmShift(dest, u5::new(2)));
+                }
+            }
+            func.ldr(dest, RegOffset(CONTEXT, dest));
+        }
+    }
+}
+
+// Helpers that can trap return the result in r0 and the trap code (or 0) in r1
+pub(crate) fn trapping_result(result: Result<u32, Trap>) -> u64 {
+    match result {
+        Ok(value) => value as u64,
+        Err(trap) => (trap as u64) << 32,
+    }
+}
+
+/// Calls the helper whose address is in `helper` on the native stack, so that host code never runs
+/// on the WASM stack
+///
+/// Pointers into the WASM stack have to be in the argument registers before the call, `sp` is back
+/// at the top of the WASM stack afterwards.
+pub(crate) fn call_native(func: &mut Emitter, helper: impl GeneralPurposeRegister) {
+    func.mov(WASM_SP, sp);
+    func.mov(sp, ARCH_SP);
+    func.blx(helper);
+    func.mov(sp, WASM_SP);
+}
+
+/// Calls a helper with the context, a pointer to its operands on the WASM stack and up to two more
+/// arguments known at compile time, then pops the operands
+///
+/// The operands are read in place, the last one first. The helper runs on the native stack.
+pub(crate) fn call_with_operands(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    helper: Helper,
+    operands: u8,
+    args: &[u32],
+) {
+    // Every argument register may be needed, so the address goes into the scratch register
+    load_helper(func, A, helper);
+    func.mov(r12, A);
+    func.movs(A, CONTEXT);
+    func.mov(B, sp);
+    for (register, arg) in [C, D].into_iter().zip(args) {
+        load_index(func, data_map, register, *arg);
+    }
+    call_native(func, r12);
+    func.add(sp, u7::new(operands)); // Pop the operands, the immediate is in words
+}
+
+/// Records `trap` in the runtime context and unwinds back to the host
+pub(crate) fn raise_trap(func: &mut Emitter, trap: Trap, unwind: Label) {
+    func.movs(r1, trap as u8);
+    func.str(r1, ImmOffset(CONTEXT, u5::new(ContextField::Unwind as u8)));
+    func.branch(unwind);
+}
+
+/// Unwinds with the trap code in `code`, unless it is zero
+pub(crate) fn check_trap(func: &mut Emitter, code: LowRegister, unwind: Label) {
+    let mut ok = func.create_label();
+    func.cmp(code, 0);
+    func.b_if(Condition::EQ, ok);
+    func.str(
+        code,
+        ImmOffset(CONTEXT, u5::new(ContextField::Unwind as u8)),
+    );
+    func.branch(unwind);
+    func.label(&mut ok);
+}
+
+// extern_func!(emitter, helper, (ARGS) -> Return reg)
 #[macro_export]
 macro_rules! extern_func {
-    ($func:ident, $data_map:ident, $extern_func:ident, (A, B) -> $reg:ident) => {{
-        use super::get_data_label;
+    ($func:ident, $helper:expr, (A, B) -> $reg:ident) => {{
+        use super::load_helper;
         use crate::aliases::*;
         use pico_emit::{instructions::*, register_list};
 
-        let extern_label = get_data_label($func, $data_map, $extern_func as u32);
-
         $func.pop(register_list!(B, C));
         $func.movs(A, C);
-        $func.ldr(C, extern_label);
+        load_helper($func, C, $helper);
         $func.blx(C);
         $func.push(register_list!($reg));
     }};
 
-    ($func:ident, $data_map:ident, $extern_func:ident, (A) -> $reg:ident) => {{
-        use super::get_data_label;
+    ($func:ident, $helper:expr, (A) -> $reg:ident) => {{
+        use super::load_helper;
         use crate::aliases::*;
         use pico_emit::{instructions::*, register_list};
 
-        let extern_label = get_data_label($func, $data_map, $extern_func as u32);
-
         $func.pop(register_list!(A));
-        $func.ldr(C, extern_label);
+        load_helper($func, C, $helper);
         $func.blx(C);
         $func.push(register_list!($reg));
     }};
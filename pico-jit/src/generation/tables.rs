@@ -0,0 +1,291 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use pico_emit::{emitter::Label, instructions::*, register_list, Emitter};
+
+use crate::aliases::*;
+use crate::context::{Helper, RuntimeContext};
+use crate::memory::WasmTable;
+use crate::wasm_module::Trap;
+
+use super::{
+    call_native, call_with_operands, check_trap, load_helper, load_index, trapping_result,
+};
+
+/// The table with the given index, or a trap if there is none
+///
+/// Compiled code only reaches tables through the helpers below, which are each done with the
+/// reference before they return, so it is the only one to that table.
+#[allow(clippy::mut_from_ref)]
+pub(crate) fn table(context: &RuntimeContext, index: u32) -> Result<&mut WasmTable, Trap> {
+    if index >= context.table_count {
+        return Err(Trap::OutOfBoundsTableAccess);
+    }
+
+    // SAFETY: The module keeps its tables alive and in place while code runs, and the index was
+    // checked against them
+    Ok(unsafe { &mut *(context.tables as *mut WasmTable).add(index as usize) })
+}
+
+/// The element segment with the given index, like `table`
+#[allow(clippy::mut_from_ref)]
+fn element_segment(context: &RuntimeContext, index: u32) -> Result<&mut Box<[u32]>, Trap> {
+    if index >= context.element_segment_count {
+        return Err(Trap::OutOfBoundsTableAccess);
+    }
+
+    // SAFETY: As for `table`, with the module's segments
+    Ok(unsafe { &mut *(context.element_segments as *mut Box<[u32]>).add(index as usize) })
+}
+
+// Checks that `len` elements starting at `start` are inside a table or segment of `size`
+fn in_bounds(size: usize, start: u32, len: u32) -> bool {
+    start
+        .checked_add(len)
+        .is_some_and(|end| end as usize <= size)
+}
+
+pub(crate) extern "C" fn table_get_checked(
+    context: &RuntimeContext,
+    table_index: u32,
+    index: u32,
+) -> u64 {
+    trapping_result(table(context, table_index).and_then(|table| {
+        table
+            .elements
+            .get(index as usize)
+            .copied()
+            .ok_or(Trap::OutOfBoundsTableAccess)
+    }))
+}
+
+/// `table.set`, `args` points at its operands on the WASM stack
+///
+/// Returns a trap code, or 0 on success.
+pub(crate) extern "C" fn table_set_checked(
+    context: &RuntimeContext,
+    args: &[u32; 2],
+    table_index: u32,
+) -> u32 {
+    // The stack grows down, so the last operand comes first
+    let [value, index] = *args;
+    let table = match table(context, table_index) {
+        Ok(table) => table,
+        Err(trap) => return trap as u32,
+    };
+
+    match table.elements.get_mut(index as usize) {
+        Some(element) => {
+            *element = value;
+            0
+        }
+        None => Trap::OutOfBoundsTableAccess as u32,
+    }
+}
+
+/// `table.size`, which can't trap, so a missing table reads as empty
+pub(crate) extern "C" fn current_table_size(context: &RuntimeContext, table_index: u32) -> u32 {
+    table(context, table_index).map_or(0, |table| table.elements.len() as u32)
+}
+
+/// `table.grow`, returns the old size or -1 if the table can't grow that far
+pub(crate) extern "C" fn grow_table(
+    context: &RuntimeContext,
+    args: &[u32; 2],
+    table_index: u32,
+) -> u32 {
+    let [delta, value] = *args;
+    let Ok(table) = table(context, table_index) else {
+        return u32::MAX;
+    };
+
+    let size = table.elements.len() as u32;
+    let Some(new_size) = size.checked_add(delta) else {
+        return u32::MAX;
+    };
+
+    if new_size > table.maximum.unwrap_or(u32::MAX)
+        || table.elements.try_reserve(delta as usize).is_err()
+    {
+        return u32::MAX;
+    }
+
+    table.elements.resize(new_size as usize, value);
+    size
+}
+
+/// `table.fill`, `args` points at its operands on the WASM stack
+///
+/// Returns a trap code, or 0 on success.
+pub(crate) extern "C" fn table_fill_checked(
+    context: &RuntimeContext,
+    args: &[u32; 3],
+    table_index: u32,
+) -> u32 {
+    let [len, value, dst] = *args;
+    let table = match table(context, table_index) {
+        Ok(table) => table,
+        Err(trap) => return trap as u32,
+    };
+
+    if !in_bounds(table.elements.len(), dst, len) {
+        return Trap::OutOfBoundsTableAccess as u32;
+    }
+
+    table.elements[dst as usize..(dst + len) as usize].fill(value);
+    0
+}
+
+/// `table.copy`, which allows the ranges to overlap when both are in the same table
+///
+/// Returns a trap code, or 0 on success.
+pub(crate) extern "C" fn table_copy_checked(
+    context: &RuntimeContext,
+    args: &[u32; 3],
+    dst_table: u32,
+    src_table: u32,
+) -> u32 {
+    let [len, src, dst] = *args;
+    let src_len = match table(context, src_table) {
+        Ok(table) => table.elements.len(),
+        Err(trap) => return trap as u32,
+    };
+    let dst_elements = match table(context, dst_table) {
+        Ok(table) => &mut table.elements,
+        Err(trap) => return trap as u32,
+    };
+
+    if !in_bounds(src_len, src, len) || !in_bounds(dst_elements.len(), dst, len) {
+        return Trap::OutOfBoundsTableAccess as u32;
+    }
+
+    let (src, dst, len) = (src as usize, dst as usize, len as usize);
+    if dst_table == src_table {
+        dst_elements.copy_within(src..src + len, dst);
+    } else if let Ok(src_table) = table(context, src_table) {
+        // Two different tables never alias, and the source table was found above
+        dst_elements[dst..dst + len].copy_from_slice(&src_table.elements[src..src + len]);
+    }
+
+    0
+}
+
+/// `table.init`, `args` points at its operands on the WASM stack
+///
+/// Returns a trap code, or 0 on success.
+pub(crate) extern "C" fn table_init_checked(
+    context: &RuntimeContext,
+    args: &[u32; 3],
+    segment: u32,
+    table_index: u32,
+) -> u32 {
+    let [len, src, dst] = *args;
+    let (segment, table) = match (
+        element_segment(context, segment),
+        table(context, table_index),
+    ) {
+        (Ok(segment), Ok(table)) => (segment, table),
+        (Err(trap), _) | (_, Err(trap)) => return trap as u32,
+    };
+
+    if !in_bounds(segment.len(), src, len) || !in_bounds(table.elements.len(), dst, len) {
+        return Trap::OutOfBoundsTableAccess as u32;
+    }
+
+    let (src, dst, len) = (src as usize, dst as usize, len as usize);
+    table.elements[dst..dst + len].copy_from_slice(&segment[src..src + len]);
+    0
+}
+
+pub(crate) extern "C" fn drop_element_segment(context: &RuntimeContext, segment: u32) {
+    if let Ok(segment) = element_segment(context, segment) {
+        *segment = Box::new([]);
+    }
+}
+
+pub(crate) fn ref_is_null(func: &mut Emitter) {
+    // Null is 0 for every reference type
+    super::i32_ops::i32_eqz(func);
+}
+
+pub(crate) fn table_get(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    table: u32,
+    unwind: Label,
+) {
+    func.pop(register_list!(C));
+    func.movs(A, CONTEXT);
+    load_index(func, data_map, B, table);
+    load_helper(func, D, Helper::TableGet);
+    func.blx(D);
+    check_trap(func, B, unwind);
+    func.push(register_list!(A));
+}
+
+pub(crate) fn table_set(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    table: u32,
+    unwind: Label,
+) {
+    call_with_operands(func, data_map, Helper::TableSet, 2, &[table]);
+    check_trap(func, A, unwind);
+}
+
+pub(crate) fn table_size(func: &mut Emitter, data_map: &mut BTreeMap<u32, Label>, table: u32) {
+    func.movs(A, CONTEXT);
+    load_index(func, data_map, B, table);
+    load_helper(func, C, Helper::TableSize);
+    func.blx(C);
+    func.push(register_list!(A));
+}
+
+pub(crate) fn table_grow(func: &mut Emitter, data_map: &mut BTreeMap<u32, Label>, table: u32) {
+    call_with_operands(func, data_map, Helper::TableGrow, 2, &[table]);
+    func.push(register_list!(A));
+}
+
+pub(crate) fn table_fill(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    table: u32,
+    unwind: Label,
+) {
+    call_with_operands(func, data_map, Helper::TableFill, 3, &[table]);
+    check_trap(func, A, unwind);
+}
+
+pub(crate) fn table_copy(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    dst_table: u32,
+    src_table: u32,
+    unwind: Label,
+) {
+    call_with_operands(
+        func,
+        data_map,
+        Helper::TableCopy,
+        3,
+        &[dst_table, src_table],
+    );
+    check_trap(func, A, unwind);
+}
+
+pub(crate) fn table_init(
+    func: &mut Emitter,
+    data_map: &mut BTreeMap<u32, Label>,
+    elem_index: u32,
+    table: u32,
+    unwind: Label,
+) {
+    call_with_operands(func, data_map, Helper::TableInit, 3, &[elem_index, table]);
+    check_trap(func, A, unwind);
+}
+
+pub(crate) fn elem_drop(func: &mut Emitter, data_map: &mut BTreeMap<u32, Label>, elem_index: u32) {
+    func.movs(A, CONTEXT);
+    load_index(func, data_map, B, elem_index);
+    load_helper(func, C, Helper::ElemDrop);
+    call_native(func, C); // Frees the segment
+}
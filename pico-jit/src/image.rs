@@ -0,0 +1,321 @@
+//! Ahead-of-time compiled code images
+//!
+//! An image holds the generated Thumb code for every function defined in a module, so that a
+//! device can install it with `WasmModule::from_wasm_with_image` instead of compiling at runtime.
+//!
+//! The binary layout is little-endian throughout:
+//!
+//! ```text
+//! magic             b"PJIT"
+//! image version     u32
+//! compiler version  u32
+//! compiler options  u32
+//! module hash       u64
+//! function count    u32
+//!   function index    u32
+//!   code length       u32 (in halfwords)
+//!   code              [u16]
+//! export count      u32
+//!   name length       u32
+//!   name              [u8]
+//!   function index    u32
+//! ```
+//!
+//! Compiled code reaches everything that belongs to the instance through the runtime context
+//! register, so the code is installed as is, without relocations.
+use crate::compiler::{compile_wasm, CompilerOptions, ModuleTypes, COMPILER_VERSION};
+use crate::wasm_module::{Result, WasmError};
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use wasmparser_nostd::Type::Func;
+use wasmparser_nostd::{ExternalKind, Parser, Payload, TypeRef};
+
+const MAGIC: &[u8; 4] = b"PJIT";
+
+/// Version of the binary layout described in the module documentation
+pub const IMAGE_VERSION: u32 = 3;
+
+#[derive(Debug, Clone)]
+pub struct ImageFunction {
+    pub index: u32,
+    pub code: Box<[u16]>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ImageExport {
+    pub name: String,
+    pub index: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct CodeImage {
+    pub compiler_version: u32,
+    pub compiler_options: CompilerOptions,
+    pub module_hash: u64,
+    pub functions: Vec<ImageFunction>,
+    pub exports: Vec<ImageExport>,
+}
+
+/// FNV-1a hash of the module bytes, used to tie compiled code to the module it came from
+pub fn module_hash(wasm_data: &[u8]) -> u64 {
+    wasm_data.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+impl CodeImage {
+    /// Compiles every function defined in the module
+    pub fn compile(wasm_data: &[u8], options: CompilerOptions) -> Result<Self> {
+        let mut types = Vec::new();
+        let mut function_types = Vec::new();
+        let mut imported_functions = 0;
+        let mut defined_functions = 0;
+        let mut globals = Vec::new();
+        let mut tags = Vec::new();
+        let mut tables = Vec::new();
+        let mut memories = Vec::new();
+        let mut elements = Vec::new();
+        let mut data_count = None;
+        let mut functions = Vec::new();
+        let mut exports = Vec::new();
+
+        for section in Parser::new(0).parse_all(wasm_data) {
+            match section? {
+                Payload::TypeSection(reader) => {
+                    for ty in reader {
+                        let Func(func_type) = ty?;
+                        types.push(func_type);
+                    }
+                }
+                Payload::ImportSection(reader) => {
+                    for import in reader {
+                        match import?.ty {
+                            TypeRef::Func(ty) => {
+                                function_types.push(ty);
+                                imported_functions += 1;
+                            }
+                            TypeRef::Global(ty) => globals.push(ty),
+                            TypeRef::Memory(ty) => memories.push(ty),
+                            _ => {}
+                        }
+                    }
+                }
+                Payload::FunctionSection(reader) => {
+                    for index in reader {
+                        function_types.push(index?);
+                    }
+                }
+                Payload::TableSection(reader) => {
+                    for table in reader {
+                        tables.push(table?);
+                    }
+                }
+                Payload::MemorySection(reader) => {
+                    for memory in reader {
+                        memories.push(memory?);
+                    }
+                }
+                Payload::ElementSection(reader) => {
+                    for element in reader {
+                        elements.push(element?.ty);
+                    }
+                }
+                Payload::DataCountSection { count, .. } => data_count = Some(count),
+                Payload::TagSection(reader) => {
+                    for tag in reader {
+                        tags.push(tag?.func_type_idx);
+                    }
+                }
+                Payload::GlobalSection(reader) => {
+                    for global in reader {
+                        globals.push(global?.ty);
+                    }
+                }
+                Payload::ExportSection(reader) => {
+                    for export in reader {
+                        let export = export?;
+                        if export.kind == ExternalKind::Func {
+                            exports.push(ImageExport {
+                                name: export.name.to_string(),
+                                index: export.index,
+                            });
+                        }
+                    }
+                }
+                Payload::CodeSectionEntry(body) => {
+                    let index = imported_functions + defined_functions;
+                    defined_functions += 1;
+                    let module = ModuleTypes {
+                        globals: &globals,
+                        types: &types,
+                        functions: &function_types,
+                        tags: &tags,
+                        tables: &tables,
+                        memories: &memories,
+                        elements: &elements,
+                        data_count,
+                    };
+                    let compiled = compile_wasm(index as u32, body, &module, &options)?;
+
+                    functions.push(ImageFunction {
+                        index: index as u32,
+                        code: compiled.data,
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        Ok(CodeImage {
+            compiler_version: COMPILER_VERSION,
+            compiler_options: options,
+            module_hash: module_hash(wasm_data),
+            functions,
+            exports,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = ImageWriter::default();
+        writer.bytes(MAGIC);
+        writer.u32(IMAGE_VERSION);
+        writer.u32(self.compiler_version);
+        writer.u32(self.compiler_options.to_bits());
+        writer.u64(self.module_hash);
+
+        writer.u32(self.functions.len() as u32);
+        for function in &self.functions {
+            writer.u32(function.index);
+            writer.u32(function.code.len() as u32);
+            for halfword in function.code.iter() {
+                writer.u16(*halfword);
+            }
+        }
+
+        writer.u32(self.exports.len() as u32);
+        for export in &self.exports {
+            writer.u32(export.name.len() as u32);
+            writer.bytes(export.name.as_bytes());
+            writer.u32(export.index);
+        }
+
+        writer.finish()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let mut reader = ImageReader::new(data);
+        if reader.bytes(MAGIC.len())? != MAGIC {
+            return Err(WasmError::InvalidImage("bad magic".to_string()));
+        }
+
+        let version = reader.u32()?;
+        if version != IMAGE_VERSION {
+            return Err(WasmError::InvalidImage(format!(
+                "image version {} is not supported",
+                version
+            )));
+        }
+
+        let compiler_version = reader.u32()?;
+        let compiler_options = CompilerOptions::from_bits(reader.u32()?);
+        let module_hash = reader.u64()?;
+
+        let function_count = reader.u32()?;
+        let mut functions = Vec::new();
+        for _ in 0..function_count {
+            let index = reader.u32()?;
+            let code_len = reader.u32()?;
+            let mut code = Vec::new();
+            for _ in 0..code_len {
+                code.push(reader.u16()?);
+            }
+
+            functions.push(ImageFunction {
+                index,
+                code: code.into_boxed_slice(),
+            });
+        }
+
+        let export_count = reader.u32()?;
+        let mut exports = Vec::new();
+        for _ in 0..export_count {
+            let len = reader.u32()? as usize;
+            let name = core::str::from_utf8(reader.bytes(len)?)
+                .map_err(|_| WasmError::InvalidImage("export name is not UTF-8".to_string()))?;
+            exports.push(ImageExport {
+                name: name.to_string(),
+                index: reader.u32()?,
+            });
+        }
+
+        Ok(CodeImage {
+            compiler_version,
+            compiler_options,
+            module_hash,
+            functions,
+            exports,
+        })
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct ImageWriter {
+    data: Vec<u8>,
+}
+
+impl ImageWriter {
+    pub(crate) fn bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub(crate) fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub(crate) fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub(crate) fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub(crate) fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub(crate) struct ImageReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> ImageReader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        ImageReader { data, position: 0 }
+    }
+
+    pub(crate) fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        // Lengths come from the image, so they can be anything
+        let end = self.position.checked_add(len);
+        let bytes = end
+            .and_then(|end| self.data.get(self.position..end))
+            .ok_or_else(|| WasmError::InvalidImage("unexpected end of image".to_string()))?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+}
@@ -4,7 +4,10 @@
 extern crate alloc;
 
 mod aliases;
+pub mod cache;
 pub mod compiler;
+mod context;
 mod generation;
+pub mod image;
 pub mod memory;
 pub mod wasm_module;
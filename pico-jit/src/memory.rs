@@ -1,62 +1,387 @@
 use alloc::boxed::Box;
 use alloc::vec;
+use alloc::vec::Vec;
+use core::marker::PhantomPinned;
+use core::ops::Deref;
+use core::pin::Pin;
+use core::slice;
+use core::str;
 
+use crate::wasm_module::{Result, WasmError};
+
+/// A table of references
+///
+/// A `funcref` is stored as the function index plus one and an `externref` as the host's handle,
+/// so null is always 0.
+#[derive(Debug)]
+pub struct WasmTable {
+    pub(crate) elements: Vec<u32>,
+    pub(crate) maximum: Option<u32>,
+}
+
+impl WasmTable {
+    pub fn new(initial: u32, maximum: Option<u32>) -> Self {
+        WasmTable {
+            elements: vec![0; initial as usize],
+            maximum,
+        }
+    }
+}
+
+/// A number that can be read from and written to a memory, always little-endian like WASM itself
+pub trait MemoryValue: Copy {
+    const SIZE: usize;
+
+    fn from_le_slice(bytes: &[u8]) -> Self;
+    fn write_le_slice(self, bytes: &mut [u8]);
+}
+
+macro_rules! memory_value {
+    ($($ty:ty),*) => {
+        $(
+            impl MemoryValue for $ty {
+                const SIZE: usize = core::mem::size_of::<$ty>();
+
+                fn from_le_slice(bytes: &[u8]) -> Self {
+                    <$ty>::from_le_bytes(bytes.try_into().expect("Slice has the size of the value"))
+                }
+
+                fn write_le_slice(self, bytes: &mut [u8]) {
+                    bytes.copy_from_slice(&self.to_le_bytes());
+                }
+            }
+        )*
+    };
+}
+
+memory_value!(i8, u8, i16, u16, i32, u32, i64, u64, f32, f64);
+
+// Where the bytes of a memory live
+#[derive(Debug)]
+enum Storage {
+    Owned(Box<[u8]>),
+    // A region the host provided for an imported memory, which outlives the module
+    Borrowed { ptr: *mut u8, len: usize },
+}
+
+/// One linear memory of a module
+///
+/// The host only gets to see it as `&LinearMemory`, or as [`LinearMemoryMut`] to change its
+/// contents, since compiled code holds its address and size.
+#[derive(Debug)]
+pub struct LinearMemory {
+    storage: Storage,
+    page_size: u32,
+}
+
+impl LinearMemory {
+    pub(crate) fn new(bytes: Box<[u8]>, page_size: u32) -> Self {
+        LinearMemory {
+            storage: Storage::Owned(bytes),
+            page_size,
+        }
+    }
+
+    /// A memory backed by a region the host keeps ownership of
+    ///
+    /// SAFETY: `region` must outlive the memory and not be accessed in any other way meanwhile
+    pub(crate) unsafe fn borrowed(region: &mut [u8], page_size: u32) -> Self {
+        LinearMemory {
+            storage: Storage::Borrowed {
+                ptr: region.as_mut_ptr(),
+                len: region.len(),
+            },
+            page_size,
+        }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        match self.storage {
+            Storage::Owned(ref bytes) => bytes,
+            // SAFETY: The region outlives the memory, see `borrowed`
+            Storage::Borrowed { ptr, len } => unsafe { slice::from_raw_parts(ptr, len) },
+        }
+    }
+
+    pub(crate) fn bytes_mut(&mut self) -> &mut [u8] {
+        match self.storage {
+            Storage::Owned(ref mut bytes) => bytes,
+            // SAFETY: The region outlives the memory and only this memory accesses it
+            Storage::Borrowed { ptr, len } => unsafe { slice::from_raw_parts_mut(ptr, len) },
+        }
+    }
+
+    /// Whether the host provided the region, rather than the module allocating it
+    pub fn is_borrowed(&self) -> bool {
+        matches!(self.storage, Storage::Borrowed { .. })
+    }
+
+    /// Size in pages, as seen by `memory.size`
+    pub fn size(&self) -> u32 {
+        self.bytes().len() as u32 / self.page_size
+    }
+
+    /// Size of a page in bytes, 64 KiB unless the module asked for a custom page size
+    pub fn page_size(&self) -> u32 {
+        self.page_size
+    }
+
+    /// The `len` bytes at `address`, or an error if they aren't all inside the memory
+    pub fn slice(&self, address: u32, len: usize) -> Result<&[u8]> {
+        let range = address as usize..(address as usize).saturating_add(len);
+        self.bytes()
+            .get(range)
+            .ok_or(WasmError::MemoryOutOfBounds { address, len })
+    }
+
+    /// Fills `buffer` with the bytes at `address`
+    pub fn read(&self, address: u32, buffer: &mut [u8]) -> Result<()> {
+        buffer.copy_from_slice(self.slice(address, buffer.len())?);
+        Ok(())
+    }
+
+    /// Reads a number, e.g. `memory.get::<f32>(address)`
+    pub fn get<T: MemoryValue>(&self, address: u32) -> Result<T> {
+        Ok(T::from_le_slice(self.slice(address, T::SIZE)?))
+    }
+
+    /// Reads a NUL-terminated UTF-8 string, without the NUL
+    pub fn read_c_str(&self, address: u32) -> Result<&str> {
+        let rest = self.bytes().get(address as usize..).unwrap_or_default();
+        let len = rest
+            .iter()
+            .position(|byte| *byte == 0)
+            .ok_or(WasmError::MemoryOutOfBounds {
+                address,
+                len: rest.len() + 1,
+            })?;
+        str::from_utf8(&rest[..len]).map_err(|_| WasmError::InvalidUtf8(address))
+    }
+
+    /// Reads a UTF-8 string stored as its length in bytes, as a `u32`, followed by the bytes
+    pub fn read_prefixed_str(&self, address: u32) -> Result<&str> {
+        let len = self.get::<u32>(address)? as usize;
+        let bytes = self.slice(address.saturating_add(4), len)?;
+        str::from_utf8(bytes).map_err(|_| WasmError::InvalidUtf8(address))
+    }
+}
+
+/// Write access to the contents of a [`LinearMemory`], which can't replace or resize it
+///
+/// Reading goes through the memory itself, which this dereferences to.
+#[derive(Debug)]
+pub struct LinearMemoryMut<'a>(&'a mut LinearMemory);
+
+impl Deref for LinearMemoryMut<'_> {
+    type Target = LinearMemory;
+
+    fn deref(&self) -> &LinearMemory {
+        self.0
+    }
+}
+
+impl LinearMemoryMut<'_> {
+    pub fn bytes_mut(&mut self) -> &mut [u8] {
+        self.0.bytes_mut()
+    }
+
+    pub fn slice_mut(&mut self, address: u32, len: usize) -> Result<&mut [u8]> {
+        let range = address as usize..(address as usize).saturating_add(len);
+        self.bytes_mut()
+            .get_mut(range)
+            .ok_or(WasmError::MemoryOutOfBounds { address, len })
+    }
+
+    /// Copies `data` to `address`, nothing is written if it doesn't fit
+    pub fn write(&mut self, address: u32, data: &[u8]) -> Result<()> {
+        self.slice_mut(address, data.len())?.copy_from_slice(data);
+        Ok(())
+    }
+
+    pub fn set<T: MemoryValue>(&mut self, address: u32, value: T) -> Result<()> {
+        value.write_le_slice(self.slice_mut(address, T::SIZE)?);
+        Ok(())
+    }
+
+    /// Writes `value` followed by a NUL, which the string must not contain itself
+    pub fn write_c_str(&mut self, address: u32, value: &str) -> Result<()> {
+        let target = self.slice_mut(address, value.len() + 1)?;
+        target[..value.len()].copy_from_slice(value.as_bytes());
+        target[value.len()] = 0;
+        Ok(())
+    }
+
+    /// Writes a string the way `read_prefixed_str` reads it
+    pub fn write_prefixed_str(&mut self, address: u32, value: &str) -> Result<()> {
+        let target = self.slice_mut(address, 4 + value.len())?;
+        (value.len() as u32).write_le_slice(&mut target[..4]);
+        target[4..].copy_from_slice(value.as_bytes());
+        Ok(())
+    }
+}
+
+/// The state of an instance that WASM code can see
+///
+/// The memory accessors without an index all use memory 0, which is the only memory of most
+/// modules.
+///
+/// Compiled code holds the addresses of its buffers, so it is pinned like the module that owns it
+/// and only ever handed out as `Pin<&mut WasmMemory>`.
 #[derive(Debug)]
 pub struct WasmMemory {
     globals: Box<[u32]>,
-    memory: Box<[u8]>,
-    table: Box<[u32]>,
+    memories: Box<[LinearMemory]>,
+    tables: Box<[WasmTable]>,
     stack: Box<[u32]>,
     stack_index: u32,
-    memory_pages: u32,
+    _pinned: PhantomPinned,
 }
 
 impl WasmMemory {
-    pub fn new(globals: Box<[u32]>, memory: Box<[u8]>, table: Box<[u32]>, stack_size: u32) -> Self {
+    pub(crate) fn new(
+        globals: Box<[u32]>,
+        memories: Box<[LinearMemory]>,
+        tables: Box<[WasmTable]>,
+        stack_size: u32,
+    ) -> Self {
         WasmMemory {
             globals,
-            memory_pages: memory.len() as u32 / 65536,
-            memory,
-            table,
+            memories,
+            tables,
             stack: vec![0; stack_size as usize].into_boxed_slice(),
             stack_index: stack_size, // Full descending stack
+            _pinned: PhantomPinned,
         }
     }
 
-    pub fn get_globals_ptr(&mut self) -> *mut u32 {
-        self.globals.as_mut_ptr()
+    // The fields of a pinned memory, which must stay where they are
+    fn fields(self: Pin<&mut Self>) -> &mut Self {
+        // SAFETY: Only what the buffers contain is changed through this, the buffers never move
+        unsafe { self.get_unchecked_mut() }
+    }
+
+    pub fn get_globals(&self) -> &[u32] {
+        &self.globals
+    }
+
+    pub fn get_globals_ptr(self: Pin<&mut Self>) -> *mut u32 {
+        self.fields().globals.as_mut_ptr()
+    }
+
+    // Memory 0, or nothing if the module has no memory
+    fn bytes(&self) -> &[u8] {
+        self.memories.first().map_or(&[], LinearMemory::bytes)
+    }
+
+    pub(crate) fn bytes_mut(&mut self) -> &mut [u8] {
+        self.memories
+            .first_mut()
+            .map_or(&mut [], LinearMemory::bytes_mut)
+    }
+
+    pub fn get_memory_ptr(self: Pin<&mut Self>) -> *mut u8 {
+        self.fields().bytes_mut().as_mut_ptr()
     }
 
-    pub fn get_memory_ptr(&mut self) -> *mut u8 {
-        self.memory.as_mut_ptr()
+    pub fn memory(&self, index: u32) -> Option<&LinearMemory> {
+        self.memories.get(index as usize)
     }
 
-    pub fn get_table_ptr(&self) -> *const u32 {
-        self.table.as_ptr()
+    pub fn memory_mut(self: Pin<&mut Self>, index: u32) -> Option<LinearMemoryMut<'_>> {
+        self.fields()
+            .memories
+            .get_mut(index as usize)
+            .map(LinearMemoryMut)
+    }
+
+    pub fn memory_count(&self) -> u32 {
+        self.memories.len() as u32
+    }
+
+    pub(crate) fn memories_mut(&mut self) -> &mut [LinearMemory] {
+        &mut self.memories
+    }
+
+    pub(crate) fn set_memories(&mut self, memories: Box<[LinearMemory]>) {
+        self.memories = memories;
+    }
+
+    fn table(&self, table: u32) -> Result<&WasmTable> {
+        self.tables
+            .get(table as usize)
+            .ok_or(WasmError::TableNotFound(table))
+    }
+
+    /// Pointer to the elements of a table, which moves when the table grows
+    pub fn get_table_ptr(&self, table: u32) -> Result<*const u32> {
+        Ok(self.table(table)?.elements.as_ptr())
+    }
+
+    pub(crate) fn tables_mut(&mut self) -> &mut [WasmTable] {
+        &mut self.tables
+    }
+
+    pub fn get_table_size(&self, table: u32) -> Result<u32> {
+        Ok(self.table(table)?.elements.len() as u32)
+    }
+
+    pub fn get_table_element(&self, table: u32, index: u32) -> Result<u32> {
+        self.table(table)?
+            .elements
+            .get(index as usize)
+            .copied()
+            .ok_or(WasmError::TableOutOfBounds { table, index })
+    }
+
+    /// Writes a table element, e.g. to hand an `externref` to the guest
+    pub fn set_table_element(
+        self: Pin<&mut Self>,
+        table: u32,
+        index: u32,
+        value: u32,
+    ) -> Result<()> {
+        let element = self
+            .fields()
+            .tables
+            .get_mut(table as usize)
+            .ok_or(WasmError::TableNotFound(table))?
+            .elements
+            .get_mut(index as usize)
+            .ok_or(WasmError::TableOutOfBounds { table, index })?;
+        *element = value;
+        Ok(())
     }
 
     pub fn get_stack_ptr(&self) -> *const u32 {
         unsafe { self.stack.as_ptr().add(self.stack_index as usize) }
     }
 
-    pub fn set_stack_ptr(&mut self, ptr: *const u32) {
-        self.stack_index = (ptr as u32 - self.stack.as_ptr() as u32) / 4;
+    /// The lowest address the stack may grow down to
+    pub(crate) fn get_stack_limit(&self) -> *const u32 {
+        self.stack.as_ptr()
+    }
+
+    pub fn set_stack_ptr(self: Pin<&mut Self>, ptr: *const u32) {
+        let this = self.fields();
+        this.stack_index = (ptr as u32 - this.stack.as_ptr() as u32) / 4;
         assert!(
-            self.stack_index <= self.stack.len() as u32,
+            this.stack_index <= this.stack.len() as u32,
             "Tried to set stack index to {} but stack size is {}",
-            self.stack_index,
-            self.stack.len()
+            this.stack_index,
+            this.stack.len()
         );
     }
 
-    pub fn push_stack(&mut self, value: u32) {
-        self.stack_index -= 1;
-        self.stack[self.stack_index as usize] = value;
+    pub fn push_stack(self: Pin<&mut Self>, value: u32) {
+        let this = self.fields();
+        this.stack_index -= 1;
+        this.stack[this.stack_index as usize] = value;
     }
 
-    pub fn pop_stack(&mut self) -> u32 {
-        let value = self.stack[self.stack_index as usize];
-        self.stack_index += 1;
+    pub fn pop_stack(self: Pin<&mut Self>) -> u32 {
+        let this = self.fields();
+        let value = this.stack[this.stack_index as usize];
+        this.stack_index += 1;
         value
     }
 
@@ -64,23 +389,39 @@ impl WasmMemory {
         self.globals[index as usize]
     }
 
-    pub fn write_memory(&mut self, index: u32, value: u8) {
-        self.memory[index as usize] = value;
+    pub fn set_global(self: Pin<&mut Self>, index: u32, value: u32) {
+        self.fields().globals[index as usize] = value;
+    }
+
+    pub fn write_memory(self: Pin<&mut Self>, index: u32, value: u8) {
+        self.fields().bytes_mut()[index as usize] = value;
     }
 
     pub fn read_memory(&self, index: u32) -> u8 {
-        self.memory[index as usize]
+        self.bytes()[index as usize]
     }
 
     pub fn read_memory32(&self, index: u32) -> u32 {
+        let memory = self.bytes();
         let index = index as usize;
-        (self.memory[index] as u32)
-            | ((self.memory[index + 1] as u32) << 8)
-            | ((self.memory[index + 2] as u32) << 16)
-            | ((self.memory[index + 3] as u32) << 24)
+        (memory[index] as u32)
+            | ((memory[index + 1] as u32) << 8)
+            | ((memory[index + 2] as u32) << 16)
+            | ((memory[index + 3] as u32) << 24)
+    }
+
+    /// Size of the memory in bytes
+    pub fn get_memory_length(&self) -> u32 {
+        self.bytes().len() as u32
     }
 
+    /// Size of the memory in pages, as seen by `memory.size`
     pub fn get_memory_size(&self) -> u32 {
-        self.memory_pages
+        self.memories.first().map_or(0, LinearMemory::size)
+    }
+
+    /// Size of a page in bytes, 64 KiB unless the module asked for a custom page size
+    pub fn get_page_size(&self) -> u32 {
+        self.memories.first().map_or(65536, LinearMemory::page_size)
     }
 }
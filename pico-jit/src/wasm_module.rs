@@ -1,15 +1,29 @@
-use crate::compiler::{compile_wasm, WasmContext};
-use crate::memory::WasmMemory;
+use crate::cache::{CacheKey, CodeCache};
+use crate::compiler::{compile_wasm, CompilerOptions, ModuleTypes, COMPILER_VERSION};
+use crate::context::{DataSegment, MemoryRegion, RuntimeContext};
+use crate::image::{module_hash, CodeImage};
+use crate::memory::{LinearMemory, LinearMemoryMut, WasmMemory, WasmTable};
 use alloc::boxed::Box;
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 use alloc::{format, vec};
 use core::fmt::{Display, Formatter};
+use core::marker::PhantomPinned;
+use core::num::NonZeroU32;
+use core::pin::Pin;
 use pico_emit::{as_fn, JitFn};
 use wasmparser_nostd::Type::Func;
-use wasmparser_nostd::{DataKind, ExternalKind, FuncType, Parser, Payload, TypeRef};
+use wasmparser_nostd::{
+    ConstExpr, DataKind, ElementItems, ElementKind, ExternalKind, FuncType, GlobalType, MemoryType,
+    Operator, Parser, Payload, TableType, TypeRef, ValType, WasmFuncType,
+};
 
-type ExternalFn<'a> = Box<dyn FnMut(&mut WasmMemory) + 'a>;
+/// Largest linear memory a module may allocate unless the host sets another limit, in bytes
+pub const DEFAULT_MEMORY_LIMIT: usize = 2 * 65536;
+
+type ExternalFn<'a> = Box<dyn FnMut(Pin<&mut WasmMemory>) + 'a>;
+// Gets the address and the most waiters to wake, returns how many were woken
+type NotifyHook<'a> = Box<dyn FnMut(Pin<&mut WasmMemory>, u32, u32) -> u32 + 'a>;
 
 pub enum WasmFunction<'a> {
     Jit {
@@ -17,6 +31,8 @@ pub enum WasmFunction<'a> {
         ty: FuncType,
         wasm_offset: usize,
         function: Option<JitFn>,
+        last_used: u32,
+        active_calls: u32,
     },
     External {
         module: String,
@@ -26,16 +42,126 @@ pub enum WasmFunction<'a> {
     },
 }
 
+impl WasmFunction<'_> {
+    pub fn ty(&self) -> &FuncType {
+        match self {
+            WasmFunction::Jit { ty, .. } | WasmFunction::External { ty, .. } => ty,
+        }
+    }
+}
+
+/// A global of the module, imported ones come first as in the global index space
+pub struct WasmGlobal {
+    pub ty: GlobalType,
+    /// Module and name of an imported global, whose value is provided by the host
+    pub import: Option<(String, String)>,
+    provided: bool,
+}
+
+// An imported memory, the host provides it before instantiation
+struct MemoryImport {
+    module: String,
+    name: String,
+    memory: Option<LinearMemory>,
+}
+
+/// The type of an import or export
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ExternType<'m> {
+    Func(&'m FuncType),
+    Table(TableType),
+    Memory(MemoryType),
+    Global(GlobalType),
+    /// The type of a tag's payload, which has no results
+    Tag(&'m FuncType),
+}
+
+impl ExternType<'_> {
+    pub fn kind(&self) -> ExternalKind {
+        match self {
+            ExternType::Func(_) => ExternalKind::Func,
+            ExternType::Table(_) => ExternalKind::Table,
+            ExternType::Memory(_) => ExternalKind::Memory,
+            ExternType::Global(_) => ExternalKind::Global,
+            ExternType::Tag(_) => ExternalKind::Tag,
+        }
+    }
+}
+
+/// Something the module imports, see `WasmModule::imports`
+#[derive(Copy, Clone, Debug)]
+pub struct Import<'m> {
+    pub module: &'m str,
+    pub name: &'m str,
+    pub kind: ExternalKind,
+    pub ty: ExternType<'m>,
+}
+
+/// Something the module exports, see `WasmModule::exports`
+#[derive(Copy, Clone, Debug)]
+pub struct Export<'m> {
+    pub name: &'m str,
+    pub kind: ExternalKind,
+    /// Index in the index space of its kind
+    pub index: u32,
+    pub ty: ExternType<'m>,
+}
+
 // pub struct CompilationReporter<'a> {
 //     pub current_time: Box<dyn Fn() -> Instant + 'a>,
 //     pub report_time: Box<dyn FnMut(Instant, Instant) + 'a>,
 // }
 
+/// An instantiated WASM module
+///
+/// Compiled functions are handed a pointer to the module's runtime context, which points back at
+/// the module, so a module is only ever handed out as a `Pin<Box<WasmModule>>` and can never move
+/// once it exists.
 pub struct WasmModule<'a> {
     pub memory: WasmMemory,
     pub functions: Vec<WasmFunction<'a>>,
+    pub globals: Vec<WasmGlobal>,
+    // Module, name, kind and index of every import, in the order of the import section
+    imports: Box<[(String, String, ExternalKind, u32)]>,
+    // Name, kind and index of every export
+    exports: Box<[(String, ExternalKind, u32)]>,
+    custom_sections: Box<[(&'a str, &'a [u8])]>,
+    table_types: Box<[TableType]>,
+    // Only the types, as the compiler sees them
+    global_types: Box<[GlobalType]>,
     // reporter: Option<CompilationReporter<'a>>,
     wasm_data: &'a [u8],
+    // Points into `wasm_data`, so passive segments are never copied
+    data_segments: Box<[DataSegment]>,
+    // References of every element segment, empty once dropped
+    element_segments: Box<[Box<[u32]>]>,
+    element_types: Box<[ValType]>,
+    data_count: Option<u32>,
+    types: Box<[FuncType]>,
+    // Type id of every function, for `call_indirect`. Equal types have the same id, which is the
+    // index of the first of them
+    function_types: Box<[u32]>,
+    // Type id of every type index
+    type_ids: Box<[u32]>,
+    // Type index of every tag
+    tags: Box<[u32]>,
+    // Imported memories come first, like in the index space
+    memory_types: Box<[MemoryType]>,
+    memory_imports: Box<[MemoryImport]>,
+    memory_limit: usize,
+    // What compiled code sees of every memory, rebuilt whenever the memories move
+    memory_regions: Box<[MemoryRegion]>,
+    start_function: Option<u32>,
+    module_hash: u64,
+    code_cache: Option<Box<dyn CodeCache + 'a>>,
+    notify_hook: Option<NotifyHook<'a>>,
+    compiler_options: CompilerOptions,
+    code_budget: Option<usize>,
+    code_size: usize,
+    clock: u32,
+    pending_error: Option<WasmError>,
+    context: RuntimeContext,
+    _pinned: PhantomPinned,
 }
 
 #[derive(Debug)]
@@ -43,12 +169,145 @@ pub enum WasmError {
     InvalidVersion(u16),
     UnsupportedImport(TypeRef),
     UnsupportedSection(String),
-    TooManyMemoryDefinitions,
-    MemorySizeTooLarge(u32),
+    MemoryTooLarge {
+        bytes: u64,
+        limit: usize,
+    },
     FunctionNotFound(String),
     ParseError(wasmparser_nostd::BinaryReaderError),
     TooManyLocals(u32),
     UnsupportedOp(String),
+    InvalidImage(String),
+    ImageMismatch(String),
+    /// The heap ran out while compiling a function, for its code, locals or blocks
+    ///
+    /// Some allocations can't report failure, so running out of memory there still aborts: the
+    /// `BTreeMap` of constants a function loads, the validator's operand and block stacks, and new
+    /// entries in `InMemoryCodeCache`. They hold one node per distinct constant, one word per
+    /// operand or block, and one node per cached function, which is small next to the code.
+    OutOfMemory,
+    CodeBudgetExceeded(usize),
+    Trap(Trap),
+    ImportNotProvided {
+        module: String,
+        name: String,
+    },
+    GlobalNotFound(String),
+    MemoryNotFound(String),
+    MemoryOutOfBounds {
+        address: u32,
+        len: usize,
+    },
+    TableNotFound(u32),
+    TableOutOfBounds {
+        table: u32,
+        index: u32,
+    },
+    InvalidUtf8(u32),
+    InvalidMemoryImport {
+        module: String,
+        name: String,
+        reason: &'static str,
+    },
+    ImmutableGlobal(u32),
+    UnsupportedType(ValType),
+    InvalidConstExpr,
+    InvalidLocal(u32),
+    InvalidExport(String),
+    StartFunctionFailed(Box<WasmError>),
+    UncaughtException(Exception),
+}
+
+/// An exception thrown by WASM code
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Exception {
+    /// Index of the exception's tag in the module
+    pub tag: u32,
+    /// The values thrown with it, in the order of the tag's params
+    pub payload: Vec<u32>,
+}
+
+/// Runtime errors raised by WASM code
+///
+/// Compiled code reports these through the runtime context, so the discriminants are part of the
+/// generated code and must never change.
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Trap {
+    Unreachable = 1,
+    IntegerOverflow = 2,
+    InvalidConversionToInteger = 3,
+    IntegerDivideByZero = 4,
+    OutOfBoundsMemoryAccess = 5,
+    OutOfBoundsTableAccess = 6,
+    IndirectCallTypeMismatch = 7,
+    UninitializedElement = 8,
+    UndefinedElement = 9,
+    UnalignedAtomic = 10,
+    StackOverflow = 11,
+}
+
+impl Trap {
+    pub(crate) fn from_code(code: u32) -> Option<Trap> {
+        match code {
+            1 => Some(Trap::Unreachable),
+            2 => Some(Trap::IntegerOverflow),
+            3 => Some(Trap::InvalidConversionToInteger),
+            4 => Some(Trap::IntegerDivideByZero),
+            5 => Some(Trap::OutOfBoundsMemoryAccess),
+            6 => Some(Trap::OutOfBoundsTableAccess),
+            7 => Some(Trap::IndirectCallTypeMismatch),
+            8 => Some(Trap::UninitializedElement),
+            9 => Some(Trap::UndefinedElement),
+            10 => Some(Trap::UnalignedAtomic),
+            11 => Some(Trap::StackOverflow),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Trap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Trap::Unreachable => write!(f, "unreachable executed"),
+            Trap::IntegerOverflow => write!(f, "integer overflow"),
+            Trap::InvalidConversionToInteger => write!(f, "invalid conversion to integer"),
+            Trap::IntegerDivideByZero => write!(f, "integer divide by zero"),
+            Trap::OutOfBoundsMemoryAccess => write!(f, "out of bounds memory access"),
+            Trap::OutOfBoundsTableAccess => write!(f, "out of bounds table access"),
+            Trap::IndirectCallTypeMismatch => write!(f, "indirect call type mismatch"),
+            Trap::UninitializedElement => write!(f, "uninitialized element"),
+            Trap::UndefinedElement => write!(f, "undefined element"),
+            Trap::UnalignedAtomic => write!(f, "unaligned atomic"),
+            Trap::StackOverflow => write!(f, "call stack exhausted"),
+        }
+    }
+}
+
+/// An opaque host value that the guest can hold as an `externref`
+///
+/// The guest can only store and pass these around. References are passed as words, e.g. in the
+/// args of `Call::call` or to `provide_global`, with null as 0, so handles are never 0.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ExternRef(NonZeroU32);
+
+impl ExternRef {
+    pub fn new(handle: NonZeroU32) -> Self {
+        ExternRef(handle)
+    }
+
+    pub fn handle(self) -> NonZeroU32 {
+        self.0
+    }
+
+    /// The word representing a nullable reference
+    pub fn to_bits(reference: Option<ExternRef>) -> u32 {
+        reference.map_or(0, |reference| reference.0.get())
+    }
+
+    pub fn from_bits(bits: u32) -> Option<ExternRef> {
+        NonZeroU32::new(bits).map(ExternRef)
+    }
 }
 
 impl From<wasmparser_nostd::BinaryReaderError> for WasmError {
@@ -65,14 +324,63 @@ impl Display for WasmError {
             WasmError::UnsupportedSection(section) => {
                 write!(f, "Unsupported section: {}", section)
             }
-            WasmError::TooManyMemoryDefinitions => {
-                write!(f, "pico-jit only supports one memory definition per module")
-            }
-            WasmError::MemorySizeTooLarge(size) => write!(f, "Memory size too large: {}", size),
+            WasmError::MemoryTooLarge { bytes, limit } => write!(
+                f,
+                "Memory of {} bytes exceeds the limit of {} bytes",
+                bytes, limit
+            ),
             WasmError::UnsupportedOp(op) => write!(f, "Unsupported op: {}", op),
             WasmError::FunctionNotFound(name) => write!(f, "Function not found: {}", name),
             WasmError::ParseError(e) => write!(f, "Parse error: {}", e),
             WasmError::TooManyLocals(count) => write!(f, "Too many locals: {}", count),
+            WasmError::InvalidImage(reason) => write!(f, "Invalid code image: {}", reason),
+            WasmError::ImageMismatch(reason) => {
+                write!(f, "Code image does not match module: {}", reason)
+            }
+            WasmError::OutOfMemory => write!(f, "Out of memory while compiling"),
+            WasmError::CodeBudgetExceeded(size) => write!(
+                f,
+                "No room for {} bytes of code within the code budget",
+                size
+            ),
+            WasmError::Trap(trap) => write!(f, "Trap: {}", trap),
+            WasmError::ImportNotProvided { module, name } => {
+                write!(f, "Import {}.{} was not provided", module, name)
+            }
+            WasmError::GlobalNotFound(name) => write!(f, "Global not found: {}", name),
+            WasmError::MemoryNotFound(name) => write!(f, "Memory not found: {}", name),
+            WasmError::MemoryOutOfBounds { address, len } => write!(
+                f,
+                "Access of {} bytes at address {} is outside the memory",
+                len, address
+            ),
+            WasmError::TableNotFound(table) => write!(f, "Table {} does not exist", table),
+            WasmError::TableOutOfBounds { table, index } => {
+                write!(f, "Element {} is outside table {}", index, table)
+            }
+            WasmError::InvalidUtf8(address) => {
+                write!(f, "String at address {} is not valid UTF-8", address)
+            }
+            WasmError::InvalidMemoryImport {
+                module,
+                name,
+                reason,
+            } => write!(f, "Memory provided for {}.{} {}", module, name, reason),
+            WasmError::ImmutableGlobal(index) => write!(f, "Global {} is immutable", index),
+            WasmError::UnsupportedType(ty) => write!(f, "Unsupported type: {:?}", ty),
+            WasmError::InvalidLocal(index) => write!(f, "Local {} does not exist", index),
+            WasmError::InvalidExport(name) => {
+                write!(f, "Export {} refers to an item that does not exist", name)
+            }
+            WasmError::InvalidConstExpr => write!(f, "Constant expression does not give one value"),
+            WasmError::StartFunctionFailed(error) => {
+                write!(f, "Start function failed during instantiation: {}", error)
+            }
+            WasmError::UncaughtException(exception) => write!(
+                f,
+                "Uncaught exception with tag {}: {:?}",
+                exception.tag, exception.payload
+            ),
         }
     }
 }
@@ -81,13 +389,129 @@ impl core::error::Error for WasmError {}
 
 pub type Result<T> = core::result::Result<T, WasmError>;
 
+/// Evaluates a constant expression, as used for global initializers and segment offsets
+///
+/// Handles the MVP forms, the extended-const arithmetic and references. `globals` holds the values
+/// of the globals the expression may read.
+fn eval_const_expr(expr: &ConstExpr, globals: &[u32]) -> Result<u32> {
+    let mut stack = Vec::with_capacity(2);
+    for op in expr.get_operators_reader() {
+        let value = match op? {
+            Operator::I32Const { value } => value as u32,
+            Operator::F32Const { value } => value.bits(),
+            // Null is 0 and functions are stored as their index plus one, as in tables
+            Operator::RefNull { .. } => 0,
+            Operator::RefFunc { function_index } => function_index + 1,
+            Operator::GlobalGet { global_index } => *globals
+                .get(global_index as usize)
+                .ok_or_else(|| WasmError::GlobalNotFound(global_index.to_string()))?,
+            op @ (Operator::I32Add | Operator::I32Sub | Operator::I32Mul) => {
+                let (Some(rhs), Some(lhs)) = (stack.pop(), stack.pop()) else {
+                    return Err(WasmError::InvalidConstExpr);
+                };
+
+                match op {
+                    Operator::I32Add => u32::wrapping_add(lhs, rhs),
+                    Operator::I32Sub => u32::wrapping_sub(lhs, rhs),
+                    _ => u32::wrapping_mul(lhs, rhs),
+                }
+            }
+            Operator::End => break,
+            op => {
+                return Err(WasmError::UnsupportedOp(format!(
+                    "{:?} in constant expression",
+                    op
+                )))
+            }
+        };
+
+        stack.push(value);
+    }
+
+    match stack[..] {
+        [value] => Ok(value),
+        _ => Err(WasmError::InvalidConstExpr),
+    }
+}
+
+// Globals are stored in one word each
+fn check_global_type(ty: &GlobalType) -> Result<()> {
+    match ty.content_type {
+        ValType::I32 | ValType::F32 | ValType::FuncRef | ValType::ExternRef => Ok(()),
+        ty => Err(WasmError::UnsupportedType(ty)),
+    }
+}
+
+// Pages are 64 KiB, or a single byte with the custom-page-sizes proposal
+fn check_memory_type(ty: &MemoryType) -> Result<()> {
+    match ty.page_size_log2 {
+        None | Some(0 | 16) => Ok(()),
+        Some(log2) => Err(WasmError::UnsupportedSection(format!(
+            "Memory with pages of 2^{} bytes",
+            log2
+        ))),
+    }
+}
+
+// Checks a region the host provides against the limits of the memory import it is for
+fn check_memory_import(ty: &MemoryType, region: &[u8]) -> core::result::Result<(), &'static str> {
+    let page_size = 1u64 << ty.page_size_log2.unwrap_or(16);
+    let pages = region.len() as u64 / page_size;
+    if !(region.len() as u64).is_multiple_of(page_size) {
+        Err("is not a whole number of pages")
+    } else if pages < ty.initial {
+        Err("is smaller than the import's minimum")
+    } else if ty.maximum.is_some_and(|maximum| pages > maximum) {
+        Err("is larger than the import's maximum")
+    } else if !region.is_empty() && !(region.as_ptr() as usize).is_multiple_of(4) {
+        // Compiled code accesses aligned words and halfwords directly
+        Err("is not word aligned")
+    } else {
+        Ok(())
+    }
+}
+
 impl<'a> WasmModule<'a> {
-    pub fn from_wasm(wasm_data: &'a [u8]) -> Result<Self> {
+    /// Instantiates a module, running its start function if it has one
+    pub fn from_wasm(wasm_data: &'a [u8]) -> Result<Pin<Box<Self>>> {
+        Self::from_wasm_with_imports(wasm_data, |_| Ok(()))
+    }
+
+    /// Instantiates a module, letting `provide_imports` add external functions before the start
+    /// function runs
+    ///
+    /// An error from `provide_imports`, e.g. from `provide_memory`, is returned as is.
+    pub fn from_wasm_with_imports(
+        wasm_data: &'a [u8],
+        provide_imports: impl FnOnce(Pin<&mut Self>) -> Result<()>,
+    ) -> Result<Pin<Box<Self>>> {
+        let mut module = Self::parse(wasm_data)?;
+        provide_imports(module.as_mut())?;
+        module.as_mut().instantiate()?;
+        Ok(module)
+    }
+
+    fn parse(wasm_data: &'a [u8]) -> Result<Pin<Box<Self>>> {
         let parser = Parser::new(0);
-        let mut memory = Vec::with_capacity(1);
+        let mut memory_types = Vec::new();
+        let mut memory_imports = Vec::new();
         let mut globals = Vec::with_capacity(1);
+        let mut global_values = Vec::with_capacity(1);
+        let mut imports = Vec::new();
+        let mut exports = Vec::new();
+        let mut custom_sections = Vec::new();
+        let mut table_types = Vec::new();
         let mut functions = Vec::new();
         let mut types = Vec::new();
+        let mut type_ids = Vec::new();
+        let mut function_types = Vec::new();
+        let mut tables = Vec::new();
+        let mut element_segments = Vec::new();
+        let mut element_types = Vec::new();
+        let mut data_count = None;
+        let mut tags = Vec::new();
+        let mut data_segments = Vec::new();
+        let mut start_function = None;
         let mut body_index = 0;
         for section in parser.parse_all(wasm_data) {
             match section? {
@@ -99,14 +523,32 @@ impl<'a> WasmModule<'a> {
                 Payload::TypeSection(reader) => {
                     for ty in reader {
                         let Func(func_type) = ty?;
+                        // Indirect calls only compare ids, so equal types must share one
+                        let id = types.iter().position(|t| *t == func_type);
+                        type_ids.push(id.unwrap_or(types.len()) as u32);
                         types.push(func_type);
                     }
                 }
                 Payload::ImportSection(reader) => {
                     for import in reader {
                         let import = import?;
+                        // Imports come first in every index space
+                        let (kind, index) = match import.ty {
+                            TypeRef::Func(_) => (ExternalKind::Func, functions.len()),
+                            TypeRef::Global(_) => (ExternalKind::Global, globals.len()),
+                            TypeRef::Memory(_) => (ExternalKind::Memory, memory_types.len()),
+                            _ => return Err(WasmError::UnsupportedImport(import.ty)),
+                        };
+                        imports.push((
+                            import.module.to_string(),
+                            import.name.to_string(),
+                            kind,
+                            index as u32,
+                        ));
+
                         match import.ty {
                             TypeRef::Func(ty) => {
+                                function_types.push(type_ids[ty as usize]);
                                 functions.push(WasmFunction::External {
                                     module: import.module.to_string(),
                                     name: import.name.to_string(),
@@ -115,98 +557,120 @@ impl<'a> WasmModule<'a> {
                                 });
                                 body_index += 1;
                             }
-                            _ => return Err(WasmError::UnsupportedImport(import.ty)),
+                            TypeRef::Global(ty) => {
+                                check_global_type(&ty)?;
+                                globals.push(WasmGlobal {
+                                    ty,
+                                    import: Some((
+                                        import.module.to_string(),
+                                        import.name.to_string(),
+                                    )),
+                                    provided: false,
+                                });
+                                global_values.push(0);
+                            }
+                            TypeRef::Memory(ty) => {
+                                check_memory_type(&ty)?;
+                                memory_types.push(ty);
+                                memory_imports.push(MemoryImport {
+                                    module: import.module.to_string(),
+                                    name: import.name.to_string(),
+                                    memory: None,
+                                });
+                            }
+                            _ => unreachable!("Unsupported imports are rejected above"),
                         }
                     }
                 }
                 Payload::FunctionSection(reader) => {
                     for index in reader {
+                        let index = index? as usize;
+                        function_types.push(type_ids[index]);
                         functions.push(WasmFunction::Jit {
                             name: None,
-                            ty: types[index? as usize].clone(),
+                            ty: types[index].clone(),
                             wasm_offset: 0,
                             function: None,
+                            last_used: 0,
+                            active_calls: 0,
                         });
                     }
                 }
-                Payload::TableSection(_) => {
-                    //unimplemented!("Table section");
+                Payload::TableSection(reader) => {
+                    for table in reader {
+                        let table = table?;
+                        tables.push(WasmTable::new(table.initial, table.maximum));
+                        table_types.push(table);
+                    }
                 }
-                Payload::MemorySection(reader) => {
-                    let mut reader_iter = reader.into_iter();
-                    if reader_iter.len() != 1 {
-                        return Err(WasmError::TooManyMemoryDefinitions);
+                Payload::TagSection(reader) => {
+                    for tag in reader {
+                        tags.push(tag?.func_type_idx);
                     }
-
-                    let mem = reader_iter.next().unwrap()?;
-                    if mem.initial > 2 {
-                        return Err(WasmError::MemorySizeTooLarge(mem.initial as u32));
+                }
+                Payload::MemorySection(reader) => {
+                    for mem in reader {
+                        let mem = mem?;
+                        check_memory_type(&mem)?;
+                        // Memories are allocated at instantiation, once the host has set the limit
+                        memory_types.push(mem);
                     }
-
-                    memory = vec![0; mem.initial as usize * 65536]; // 65536 bytes per page
                 }
                 Payload::GlobalSection(reader) => {
                     for global in reader {
-                        // TODO: Care about the type of the global (when 64-bit stuff is added)
-                        let op_reader = global?.init_expr.get_operators_reader();
-                        match op_reader.into_iter().next().unwrap()? {
-                            wasmparser_nostd::Operator::I32Const { value } => {
-                                globals.push(value as u32);
-                            }
-                            wasmparser_nostd::Operator::F32Const { value } => {
-                                globals.push(value.bits());
-                            }
-                            op => {
-                                return Err(WasmError::UnsupportedOp(format!(
-                                    "{:?} in global section",
-                                    op
-                                )))
-                            }
-                        }
+                        let global = global?;
+                        check_global_type(&global.ty)?;
+                        globals.push(WasmGlobal {
+                            ty: global.ty,
+                            import: None,
+                            provided: true,
+                        });
+
+                        // Initializers may read imported globals, so they run at instantiation
+                        global_values.push(0);
                     }
                 }
                 Payload::ExportSection(reader) => {
                     for export in reader {
                         let export = export?;
+                        exports.push((export.name.to_string(), export.kind, export.index));
                         if export.kind != ExternalKind::Func {
                             continue;
                         }
 
-                        let Some(WasmFunction::Jit { ref mut name, .. }) =
+                        // Re-exported imports keep their import name
+                        if let Some(WasmFunction::Jit { ref mut name, .. }) =
                             functions.get_mut(export.index as usize)
-                        else {
-                            unreachable!();
-                        };
-
-                        *name = Some(export.name.to_string());
+                        {
+                            *name = Some(export.name.to_string());
+                        }
                     }
                 }
                 Payload::DataSection(reader) => {
                     for data in reader {
                         let data = data?;
 
-                        let DataKind::Active { offset_expr, .. } = data.kind else {
-                            todo!("DataKind::Passive not supported yet")
+                        let DataKind::Active { .. } = data.kind else {
+                            data_segments.push(DataSegment {
+                                data: data.data.as_ptr(),
+                                len: data.data.len() as u32,
+                            });
+                            continue;
                         };
 
-                        match offset_expr
-                            .get_operators_reader()
-                            .into_iter()
-                            .next()
-                            .unwrap()?
-                        {
-                            wasmparser_nostd::Operator::I32Const { value } => {
-                                let offset = value as usize;
-                                let data = data.data;
-                                memory[offset..offset + data.len()].copy_from_slice(data);
-                            }
-                            op => {
-                                return Err(WasmError::UnsupportedOp(format!(
-                                    "{:?} as data offset",
-                                    op
-                                )))
-                            }
-                        };
+                        // Active segments behave as if dropped once they have been copied, which
+                        // happens at instantiation
+                        data_segments.push(DataSegment {
+                            data: data.data.as_ptr(),
+                            len: 0,
+                        });
+                    }
+                }
+                Payload::ElementSection(reader) => {
+                    for element in reader {
+                        element_types.push(element?.ty);
+                        // Elements may read globals, so they are evaluated at instantiation
+                        element_segments.push(Box::default());
                     }
                 }
                 Payload::CodeSectionEntry(body) => {
@@ -222,111 +686,923 @@ impl<'a> WasmModule<'a> {
                     *wasm_offset = reader.original_position();
                     body_index += 1;
                 }
-                Payload::StartSection { .. }
-                | Payload::End(_)
-                | Payload::CustomSection(_)
-                | Payload::DataCountSection { .. }
-                | Payload::CodeSectionStart { .. } => {}
+                Payload::StartSection { func, .. } => start_function = Some(func),
+                Payload::CustomSection(reader) => {
+                    custom_sections.push((reader.name(), reader.data()));
+                }
+                Payload::DataCountSection { count, .. } => data_count = Some(count),
+                Payload::End(_) | Payload::CodeSectionStart { .. } => {}
                 section => {
                     return Err(WasmError::UnsupportedSection(format!("{:?}", section)));
                 }
             }
         }
 
-        Ok(WasmModule {
+        let mut module = Box::pin(WasmModule {
             memory: WasmMemory::new(
-                globals.into_boxed_slice(),
-                memory.into_boxed_slice(),
-                vec![0].into_boxed_slice(),
+                global_values.into_boxed_slice(),
+                Box::default(),
+                tables.into_boxed_slice(),
                 1024,
             ),
             functions,
+            global_types: globals.iter().map(|global| global.ty).collect(),
+            globals,
+            imports: imports.into_boxed_slice(),
+            exports: exports.into_boxed_slice(),
+            custom_sections: custom_sections.into_boxed_slice(),
+            table_types: table_types.into_boxed_slice(),
             wasm_data,
-        })
+            data_segments: data_segments.into_boxed_slice(),
+            element_segments: element_segments.into_boxed_slice(),
+            element_types: element_types.into_boxed_slice(),
+            data_count,
+            function_types: function_types.into_boxed_slice(),
+            type_ids: type_ids.into_boxed_slice(),
+            types: types.into_boxed_slice(),
+            tags: tags.into_boxed_slice(),
+            memory_types: memory_types.into_boxed_slice(),
+            memory_imports: memory_imports.into_boxed_slice(),
+            memory_limit: DEFAULT_MEMORY_LIMIT,
+            memory_regions: Box::default(),
+            start_function,
+            module_hash: module_hash(wasm_data),
+            code_cache: None,
+            notify_hook: None,
+            compiler_options: CompilerOptions::default(),
+            code_budget: None,
+            code_size: 0,
+            clock: 0,
+            pending_error: None,
+            context: RuntimeContext::new(),
+            _pinned: PhantomPinned,
+        });
+
+        // Only function bodies are validated, as they are compiled, so an export of a missing
+        // item is rejected here rather than when the host looks at its type
+        if let Some((name, _, _)) = module
+            .exports
+            .iter()
+            .find(|(_, kind, index)| module.extern_type(*kind, *index).is_none())
+        {
+            return Err(WasmError::InvalidExport(name.clone()));
+        }
+
+        // SAFETY: The module is only used to fill in its own context, it is never moved
+        unsafe { module.as_mut().get_unchecked_mut().attach_context() };
+
+        Ok(module)
+    }
+
+    /// Instantiates a module using code compiled ahead of time by `CodeImage::compile`
+    ///
+    /// Every function in the image is installed as is, so none of them are compiled at runtime.
+    /// `provide_imports` is called like for `from_wasm_with_imports`.
+    pub fn from_wasm_with_image(
+        wasm_data: &'a [u8],
+        image: &[u8],
+        provide_imports: impl FnOnce(Pin<&mut Self>) -> Result<()>,
+    ) -> Result<Pin<Box<Self>>> {
+        let image = CodeImage::from_bytes(image)?;
+        if image.compiler_version != COMPILER_VERSION {
+            return Err(WasmError::ImageMismatch(format!(
+                "compiled by compiler version {}, expected {}",
+                image.compiler_version, COMPILER_VERSION
+            )));
+        }
+
+        let mut module = Self::parse(wasm_data)?;
+        if image.module_hash != module.module_hash {
+            return Err(WasmError::ImageMismatch("module hash differs".to_string()));
+        }
+
+        // SAFETY: Only the function table is modified, the module itself is never moved
+        let this = unsafe { module.as_mut().get_unchecked_mut() };
+        // Anything compiled later, e.g. after eviction, has to match the image
+        this.compiler_options = image.compiler_options;
+
+        for export in &image.exports {
+            match this.functions.get(export.index as usize) {
+                Some(WasmFunction::Jit {
+                    name: Some(name), ..
+                }) if *name == export.name => {}
+                _ => {
+                    return Err(WasmError::ImageMismatch(format!(
+                        "export {} is not function {}",
+                        export.name, export.index
+                    )))
+                }
+            }
+        }
+
+        for image_function in image.functions {
+            let Some(WasmFunction::Jit { function, .. }) =
+                this.functions.get_mut(image_function.index as usize)
+            else {
+                return Err(WasmError::ImageMismatch(format!(
+                    "function {} is not defined by the module",
+                    image_function.index
+                )));
+            };
+
+            let code = image_function.code;
+            this.code_size += code.len() * 2;
+            function.replace(JitFn { data: code });
+        }
+
+        provide_imports(module.as_mut())?;
+        module.as_mut().instantiate()?;
+        Ok(module)
+    }
+
+    /// Sets the cache that is consulted before compiling a function and filled in afterwards
+    ///
+    /// Pass `&mut cache` to keep ownership, e.g. to save the cache once the module is done.
+    pub fn set_code_cache(self: Pin<&mut Self>, cache: impl CodeCache + 'a) {
+        // SAFETY: Only the cache is replaced, the module itself is never moved
+        unsafe { self.get_unchecked_mut() }.code_cache = Some(Box::new(cache));
+    }
+
+    /// Sets the most linear memory the module may allocate, in bytes across all its memories
+    ///
+    /// Memory is allocated at instantiation, so this has to be called from the `provide_imports`
+    /// callback of `from_wasm_with_imports` or `from_wasm_with_image`. The default is
+    /// `DEFAULT_MEMORY_LIMIT`. Imported memories are provided by the host and don't count towards
+    /// it.
+    pub fn set_memory_limit(self: Pin<&mut Self>, bytes: usize) {
+        // SAFETY: Only the limit is changed, the module itself is never moved
+        unsafe { self.get_unchecked_mut() }.memory_limit = bytes;
+    }
+
+    // Allocates exactly as much memory as the module declares, after the imported memories
+    fn allocate_memory(&mut self) -> Result<()> {
+        let defined = &self.memory_types[self.memory_imports.len()..];
+        let bytes = defined
+            .iter()
+            .map(|ty| ty.initial << ty.page_size_log2.unwrap_or(16))
+            .sum();
+        if bytes > self.memory_limit as u64 {
+            return Err(WasmError::MemoryTooLarge {
+                bytes,
+                limit: self.memory_limit,
+            });
+        }
+
+        let imported = self
+            .memory_imports
+            .iter_mut()
+            .filter_map(|import| import.memory.take());
+        let memories = imported
+            .chain(defined.iter().map(|ty| {
+                let page_size_log2 = ty.page_size_log2.unwrap_or(16);
+                let bytes = vec![0; (ty.initial << page_size_log2) as usize];
+                LinearMemory::new(bytes.into_boxed_slice(), 1 << page_size_log2)
+            }))
+            .collect();
+        self.memory.set_memories(memories);
+        // The memories have moved, so the context has to point at the new ones
+        self.attach_context();
+        Ok(())
+    }
+
+    /// Fills in the instance-specific part of the runtime context once the module has its final
+    /// address
+    fn attach_context(&mut self) {
+        self.context.module = self as *mut WasmModule as u32;
+        self.context.call_func = Self::compile_and_execute as *const () as u32;
+        self.context.memory = self.memory_pin().get_memory_ptr() as u32;
+        self.context.globals = self.memory_pin().get_globals_ptr() as u32;
+        self.context.stack_limit = self.memory.get_stack_limit() as u32;
+        self.context.memory_size = self.memory.get_memory_size();
+        self.context.memory_length = self.memory.get_memory_length();
+        self.context.data_segments = self.data_segments.as_mut_ptr() as u32;
+        self.context.data_segment_count = self.data_segments.len() as u32;
+        let tables = self.memory.tables_mut();
+        self.context.table_count = tables.len() as u32;
+        self.context.tables = tables.as_mut_ptr() as u32;
+        self.context.element_segments = self.element_segments.as_mut_ptr() as u32;
+        self.context.element_segment_count = self.element_segments.len() as u32;
+        self.context.function_types = self.function_types.as_ptr() as u32;
+        self.context.function_count = self.function_types.len() as u32;
+        self.context.type_ids = self.type_ids.as_ptr() as u32;
+        self.context.type_count = self.type_ids.len() as u32;
+
+        self.memory_regions = self
+            .memory
+            .memories_mut()
+            .iter_mut()
+            .map(|memory| MemoryRegion {
+                base: memory.bytes_mut().as_mut_ptr() as u32,
+                length: memory.bytes().len() as u32,
+                size: memory.size(),
+            })
+            .collect();
+        self.context.memories = self.memory_regions.as_ptr() as u32;
+        self.context.memory_count = self.memory_regions.len() as u32;
+    }
+
+    /// Gives mutable access to the module's memory
+    ///
+    /// The memory stays pinned, so writing through this never changes an address that compiled
+    /// code relies on.
+    pub fn memory_mut(self: Pin<&mut Self>) -> Pin<&mut WasmMemory> {
+        // SAFETY: The memory is pinned along with the module that owns it
+        unsafe { self.map_unchecked_mut(|module| &mut module.memory) }
+    }
+
+    // The same for code that already has the module's fields, which only exist pinned
+    fn memory_pin(&mut self) -> Pin<&mut WasmMemory> {
+        Self::pin_memory(&mut self.memory)
     }
 
+    // Pins a module's memory while other fields are borrowed, it must not be any other memory
+    fn pin_memory(memory: &mut WasmMemory) -> Pin<&mut WasmMemory> {
+        // SAFETY: Modules are only ever handed out pinned, and their memory with them
+        unsafe { Pin::new_unchecked(memory) }
+    }
+
+    // Only ever called on a pinned module, from JIT code
+    //
+    // Errors can't be returned to compiled code, so they are stored and the JIT frames are unwound
+    // back to the host instead.
     fn compile_and_execute(&mut self, function_index: u32, sp: *const u32) -> *const u32 {
-        self.memory.set_stack_ptr(sp);
-
-        let context = WasmContext {
-            module_ptr: self as *const WasmModule,
-            call_func: Self::compile_and_execute,
-            memory_ptr: self.memory.get_memory_ptr(),
-            global_ptr: self.memory.get_globals_ptr(),
-            memory_size: self.memory.get_memory_size(),
-        };
+        match self.execute(function_index, sp) {
+            Ok(sp) => sp,
+            Err(error) => {
+                self.pending_error = Some(error);
+                self.context.unwind = 1;
+                sp
+            }
+        }
+    }
 
-        let Some(func) = self.functions.get_mut(function_index as usize) else {
-            unreachable!("Function not found at index {}", function_index);
-        };
+    /// Starts unwinding with an exception thrown by compiled code
+    pub(crate) fn throw(&mut self, exception: Exception) {
+        self.pending_error = Some(WasmError::UncaughtException(exception));
+        self.context.unwind = 1;
+    }
 
-        match func {
-            WasmFunction::External { function, .. } => {
-                let function = function.as_mut().unwrap();
-                function(&mut self.memory);
-                self.memory.get_stack_ptr()
+    /// Stops unwinding if the error is an exception with the given tag, or any tag for `None`
+    ///
+    /// Traps can't be caught, so they are left alone.
+    pub(crate) fn catch(&mut self, tag: Option<u32>) -> Option<Exception> {
+        match &self.pending_error {
+            Some(WasmError::UncaughtException(exception))
+                if tag.is_none_or(|tag| tag == exception.tag) => {}
+            _ => return None,
+        }
+
+        self.context.unwind = 0;
+        match self.pending_error.take() {
+            Some(WasmError::UncaughtException(exception)) => Some(exception),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Sets what `memory.atomic.notify` does
+    ///
+    /// The hook gets the address and the most waiters to wake, and returns how many it woke.
+    /// Without a hook there are never any waiters, so nothing is woken.
+    pub fn set_notify_hook(self: Pin<&mut Self>, hook: NotifyHook<'a>) {
+        // SAFETY: Only the hook is replaced, the module itself is never moved
+        unsafe { self.get_unchecked_mut() }.notify_hook = Some(hook);
+    }
+
+    pub(crate) fn atomic_notify(&mut self, address: u32, count: u32) -> u32 {
+        match self.notify_hook.as_mut() {
+            Some(hook) => hook(Self::pin_memory(&mut self.memory), address, count),
+            None => 0,
+        }
+    }
+
+    fn execute(&mut self, function_index: u32, sp: *const u32) -> Result<*const u32> {
+        let mut sp = self.execute_function(function_index, sp)?;
+
+        // A tail call returns first and leaves the call to us, so tail calls never nest
+        loop {
+            match core::mem::take(&mut self.context.tail_call) {
+                0 => return Ok(sp),
+                next => sp = self.execute_function(next - 1, sp)?,
             }
-            WasmFunction::Jit {
-                function: jit_fn,
-                wasm_offset,
-                ty,
+        }
+    }
+
+    fn execute_function(&mut self, function_index: u32, sp: *const u32) -> Result<*const u32> {
+        self.memory_pin().set_stack_ptr(sp);
+
+        let index = function_index as usize;
+        match self.functions.get_mut(index) {
+            Some(WasmFunction::External {
+                module,
+                name,
+                function,
                 ..
-            } => {
-                if jit_fn.is_none() {
-                    // let start_time = self.reporter.as_ref().map(|r| (*r.current_time)());
-                    let parser = Parser::new(0);
-
-                    // TODO: This is a horrible, no good hack
-                    for payload in parser.parse_all(self.wasm_data) {
-                        if let Payload::CodeSectionEntry(body) = payload.unwrap() {
-                            if body.get_binary_reader().original_position() != *wasm_offset {
-                                continue;
-                            }
+            }) => {
+                let Some(function) = function.as_mut() else {
+                    return Err(WasmError::ImportNotProvided {
+                        module: module.clone(),
+                        name: name.clone(),
+                    });
+                };
+
+                function(Self::pin_memory(&mut self.memory));
+                return Ok(self.memory.get_stack_ptr());
+            }
+            Some(WasmFunction::Jit { function: None, .. }) => self.install(function_index)?,
+            Some(WasmFunction::Jit { .. }) => {}
+            None => unreachable!("Function not found at index {}", function_index),
+        }
 
-                            let compiled = compile_wasm(&context, ty, body).unwrap();
+        self.clock += 1;
+        let WasmFunction::Jit {
+            function: Some(jit_fn),
+            last_used,
+            active_calls,
+            ..
+        } = &mut self.functions[index]
+        else {
+            unreachable!();
+        };
 
-                            jit_fn.replace(compiled);
-                        }
+        // A function that is running can't be evicted, so its code stays valid for the call
+        *last_used = self.clock;
+        *active_calls += 1;
+        let function = as_fn!(jit_fn, (*const u32, *const RuntimeContext) -> *const u32);
+
+        let sp = function(sp, &self.context);
+
+        if let WasmFunction::Jit { active_calls, .. } = &mut self.functions[index] {
+            *active_calls -= 1;
+        }
+
+        if self.context.unwind != 0 {
+            // Either a trap raised by the code itself, or an error from a function it called
+            let code = core::mem::take(&mut self.context.unwind);
+            return Err(self.pending_error.take().unwrap_or_else(|| {
+                WasmError::Trap(Trap::from_code(code).expect("Unknown trap code"))
+            }));
+        }
+
+        Ok(sp)
+    }
+
+    /// Installs the code for a function, from the cache if possible and compiling it otherwise
+    fn install(&mut self, function_index: u32) -> Result<()> {
+        let cache_key = CacheKey {
+            module_hash: self.module_hash,
+            function_index,
+            compiler_version: COMPILER_VERSION,
+            compiler_options: self.compiler_options.to_bits(),
+        };
+
+        let cached = self.code_cache.as_mut().and_then(|c| c.get(&cache_key));
+        let compiled = match cached {
+            Some(data) => JitFn { data },
+            None => {
+                let compiled = match self.compile(function_index) {
+                    // Give the allocator everything we can spare and try once more
+                    Err(WasmError::OutOfMemory) if self.evict_all(function_index) => {
+                        self.compile(function_index)?
                     }
-                    // let end_time = self.reporter.as_ref().map(|r| (*r.current_time)());
-                    // if let Some(ref mut reporter) = self.reporter {
-                    //     (*reporter.report_time)(start_time.unwrap(), end_time.unwrap());
-                    // }
+                    result => result?,
+                };
+
+                if let Some(cache) = self.code_cache.as_mut() {
+                    cache.insert(cache_key, &compiled.data);
+                }
+
+                compiled
+            }
+        };
+
+        let size = compiled.data.len() * 2;
+        self.make_room(size, function_index)?;
+        self.code_size += size;
+
+        if let WasmFunction::Jit { function, .. } = &mut self.functions[function_index as usize] {
+            function.replace(compiled);
+        }
+
+        Ok(())
+    }
+
+    fn compile(&self, function_index: u32) -> Result<JitFn> {
+        let WasmFunction::Jit { wasm_offset, .. } = &self.functions[function_index as usize] else {
+            unreachable!("Only JIT functions are compiled");
+        };
+
+        // let start_time = self.reporter.as_ref().map(|r| (*r.current_time)());
+        let parser = Parser::new(0);
+
+        // TODO: This is a horrible, no good hack
+        for payload in parser.parse_all(self.wasm_data) {
+            if let Payload::CodeSectionEntry(body) = payload? {
+                if body.get_binary_reader().original_position() != *wasm_offset {
+                    continue;
                 }
 
-                let function = jit_fn.as_ref().unwrap();
-                let function = as_fn!(function, (*const u32) -> *const u32);
+                let module = ModuleTypes {
+                    globals: &self.global_types,
+                    types: &self.types,
+                    functions: &self.function_types,
+                    tags: &self.tags,
+                    tables: &self.table_types,
+                    memories: &self.memory_types,
+                    elements: &self.element_types,
+                    data_count: self.data_count,
+                };
+                return compile_wasm(function_index, body, &module, &self.compiler_options);
+            }
+        }
+        // let end_time = self.reporter.as_ref().map(|r| (*r.current_time)());
+        // if let Some(ref mut reporter) = self.reporter {
+        //     (*reporter.report_time)(start_time.unwrap(), end_time.unwrap());
+        // }
+
+        unreachable!("Function body not found at offset {}", wasm_offset)
+    }
+
+    /// Evicts compiled functions until `size` more bytes of code fit in the budget
+    fn make_room(&mut self, size: usize, keep: u32) -> Result<()> {
+        let Some(budget) = self.code_budget else {
+            return Ok(());
+        };
 
-                function(sp)
+        while self.code_size + size > budget {
+            if !self.evict_one(keep) {
+                return Err(WasmError::CodeBudgetExceeded(size));
             }
         }
+
+        Ok(())
+    }
+
+    /// Evicts every compiled function that isn't running, returns whether anything was evicted
+    fn evict_all(&mut self, keep: u32) -> bool {
+        let mut evicted = false;
+        while self.evict_one(keep) {
+            evicted = true;
+        }
+
+        evicted
     }
 
-    fn internal_call(&mut self, name: &str, args: &[u32]) -> Result<()> {
-        // Find the function index by name
-        let index = self
+    /// Returns the least recently used function that isn't running to the uncompiled state
+    fn evict_one(&mut self, keep: u32) -> bool {
+        let victim = self
             .functions
+            .iter()
+            .enumerate()
+            .filter_map(|(i, f)| match f {
+                WasmFunction::Jit {
+                    function: Some(_),
+                    last_used,
+                    active_calls: 0,
+                    ..
+                } if i != keep as usize => Some((i, *last_used)),
+                _ => None,
+            })
+            .min_by_key(|(_, last_used)| *last_used);
+
+        let Some((index, _)) = victim else {
+            return false;
+        };
+
+        if let WasmFunction::Jit { function, .. } = &mut self.functions[index] {
+            let evicted = function.take().unwrap();
+            self.code_size -= evicted.data.len() * 2;
+        }
+
+        true
+    }
+
+    /// Limits the amount of heap used by compiled code
+    ///
+    /// Once compiling a function would exceed the budget, the least recently used functions that
+    /// aren't currently running are dropped and compiled again when they are next called. Only
+    /// the code counts towards the budget, see `WasmError::OutOfMemory` for what else compiling
+    /// allocates.
+    pub fn set_code_budget(self: Pin<&mut Self>, bytes: usize) -> Result<()> {
+        // SAFETY: Only the function table is modified, the module itself is never moved
+        let this = unsafe { self.get_unchecked_mut() };
+        this.code_budget = Some(bytes);
+        this.make_room(0, u32::MAX)
+    }
+
+    /// Changes how functions are compiled from now on
+    ///
+    /// Compiled functions that aren't running are dropped, so they get compiled again with the new
+    /// options on their next call.
+    pub fn set_compiler_options(self: Pin<&mut Self>, options: CompilerOptions) {
+        // SAFETY: Only the function table is modified, the module itself is never moved
+        let this = unsafe { self.get_unchecked_mut() };
+        if this.compiler_options != options {
+            this.compiler_options = options;
+            this.evict_all(u32::MAX);
+        }
+    }
+
+    /// Number of bytes of compiled code currently held by the module
+    pub fn code_size(&self) -> usize {
+        self.code_size
+    }
+
+    fn exported_function(&self, name: &str) -> Result<u32> {
+        self.functions
             .iter()
             .enumerate()
             .find_map(|(i, f)| match f {
                 WasmFunction::Jit { name: Some(n), .. } if n == name => Some(i as u32),
                 _ => None,
             })
-            .ok_or_else(|| WasmError::FunctionNotFound(name.to_string()))?;
+            .ok_or_else(|| WasmError::FunctionNotFound(name.to_string()))
+    }
+
+    fn internal_call(self: Pin<&mut Self>, name: &str, args: &[u32]) -> Result<()> {
+        let index = self.exported_function(name)?;
+        self.call_index(index, args)
+    }
+
+    fn call_index(self: Pin<&mut Self>, index: u32, args: &[u32]) -> Result<()> {
+        // SAFETY: The module is not moved out of the pin, compiled code can rely on its address
+        let this = unsafe { self.get_unchecked_mut() };
+
+        let base = this.memory.get_stack_ptr();
 
         // Push args on to stack
         for arg in args {
-            self.memory.push_stack(*arg);
+            this.memory_pin().push_stack(*arg);
         }
 
         // Call the function
-        let sp = self.compile_and_execute(index, self.memory.get_stack_ptr());
-        self.memory.set_stack_ptr(sp);
+        match this.execute(index, this.memory.get_stack_ptr()) {
+            Ok(sp) => {
+                this.memory_pin().set_stack_ptr(sp);
+                Ok(())
+            }
+            Err(error) => {
+                // Unwound frames leave the stack in an unknown state
+                this.memory_pin().set_stack_ptr(base);
+                Err(error)
+            }
+        }
+    }
+
+    fn instantiate(mut self: Pin<&mut Self>) -> Result<()> {
+        // Initializers and code may read any global, so every imported one must have a value by now
+        if let Some((module, name)) = self
+            .globals
+            .iter()
+            .filter(|global| !global.provided)
+            .find_map(|global| global.import.clone())
+        {
+            return Err(WasmError::ImportNotProvided { module, name });
+        }
+
+        // Memories too, since data segments are copied into them
+        if let Some(import) = self
+            .memory_imports
+            .iter()
+            .find(|import| import.memory.is_none())
+        {
+            return Err(WasmError::ImportNotProvided {
+                module: import.module.clone(),
+                name: import.name.clone(),
+            });
+        }
+
+        // SAFETY: Only globals and memory are written, the module itself is never moved
+        let this = unsafe { self.as_mut().get_unchecked_mut() };
+        this.allocate_memory()?;
+        this.initialize()?;
+
+        let Some(index) = self.start_function else {
+            return Ok(());
+        };
+
+        self.call_index(index, &[])
+            .map_err(|error| WasmError::StartFunctionFailed(Box::new(error)))
+    }
+
+    // Evaluates the global initializers and element segments, and copies the active segments into
+    // the tables and memory
+    fn initialize(&mut self) -> Result<()> {
+        let imported_globals = self.globals.iter().filter(|g| g.import.is_some()).count();
+        let mut global_index = imported_globals;
+        let mut element_index = 0;
+
+        for section in Parser::new(0).parse_all(self.wasm_data) {
+            match section? {
+                Payload::GlobalSection(reader) => {
+                    for global in reader {
+                        // Only the globals before this one may be referenced
+                        let globals = &self.memory.get_globals()[..global_index];
+                        let value = eval_const_expr(&global?.init_expr, globals)?;
+                        self.memory_pin().set_global(global_index as u32, value);
+                        global_index += 1;
+                    }
+                }
+                Payload::ElementSection(reader) => {
+                    for element in reader {
+                        let element = element?;
+                        let globals = self.memory.get_globals();
+                        let references = match element.items {
+                            ElementItems::Functions(reader) => reader
+                                .into_iter()
+                                .map(|index| Ok(index? + 1))
+                                .collect::<Result<Box<[u32]>>>()?,
+                            ElementItems::Expressions(reader) => reader
+                                .into_iter()
+                                .map(|expr| eval_const_expr(&expr?, globals))
+                                .collect::<Result<Box<[u32]>>>()?,
+                        };
+
+                        // Active and declared segments behave as if dropped after instantiation
+                        if let ElementKind::Active {
+                            table_index,
+                            offset_expr,
+                        } = element.kind
+                        {
+                            let offset = eval_const_expr(&offset_expr, globals)? as usize;
+                            let table = self
+                                .memory
+                                .tables_mut()
+                                .get_mut(table_index as usize)
+                                .ok_or(WasmError::Trap(Trap::OutOfBoundsTableAccess))?;
+                            let Some(target) = offset
+                                .checked_add(references.len())
+                                .and_then(|end| table.elements.get_mut(offset..end))
+                            else {
+                                return Err(WasmError::Trap(Trap::OutOfBoundsTableAccess));
+                            };
+
+                            target.copy_from_slice(&references);
+                        } else if let ElementKind::Passive = element.kind {
+                            self.element_segments[element_index] = references;
+                        }
+
+                        element_index += 1;
+                    }
+                }
+                Payload::DataSection(reader) => {
+                    for data in reader {
+                        let data = data?;
+                        let DataKind::Active {
+                            memory_index,
+                            offset_expr,
+                        } = data.kind
+                        else {
+                            continue;
+                        };
+
+                        let offset = eval_const_expr(&offset_expr, self.memory.get_globals())?;
+                        let Some(mut memory) = self.memory_pin().memory_mut(memory_index) else {
+                            return Err(WasmError::Trap(Trap::OutOfBoundsMemoryAccess));
+                        };
+
+                        let memory = memory.bytes_mut();
+                        let Some(target) = (offset as usize)
+                            .checked_add(data.data.len())
+                            .and_then(|end| memory.get_mut(offset as usize..end))
+                        else {
+                            return Err(WasmError::Trap(Trap::OutOfBoundsMemoryAccess));
+                        };
+
+                        target.copy_from_slice(data.data);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sets the value of an imported global, which must happen before the module is instantiated
+    pub fn provide_global(
+        self: Pin<&mut Self>,
+        module: &str,
+        name: &str,
+        value: u32,
+    ) -> Result<()> {
+        // SAFETY: Only the globals are modified, the module itself is never moved
+        let this = unsafe { self.get_unchecked_mut() };
+        let index = this
+            .globals
+            .iter()
+            .position(|global| {
+                global
+                    .import
+                    .as_ref()
+                    .is_some_and(|(m, n)| m == module && n == name)
+            })
+            .ok_or_else(|| WasmError::GlobalNotFound(format!("{}.{}", module, name)))?;
+
+        this.globals[index].provided = true;
+        this.memory_pin().set_global(index as u32, value);
+        Ok(())
+    }
+
+    /// Provides an imported memory backed by a region the host keeps, e.g. a static buffer or one
+    /// shared with DMA
+    ///
+    /// The region must be word aligned and a whole number of pages within the import's limits.
+    /// Like every import, it must be provided before the module is instantiated.
+    pub fn provide_memory(
+        self: Pin<&mut Self>,
+        module: &str,
+        name: &str,
+        region: &'a mut [u8],
+    ) -> Result<()> {
+        self.provide_linear_memory(module, name, |page_size| {
+            // SAFETY: The region is borrowed for as long as the module lives
+            unsafe { LinearMemory::borrowed(region, page_size) }
+        })
+    }
+
+    /// Provides an imported memory that the module takes ownership of
+    ///
+    /// The same requirements as for `provide_memory` apply.
+    pub fn provide_owned_memory(
+        self: Pin<&mut Self>,
+        module: &str,
+        name: &str,
+        memory: Box<[u8]>,
+    ) -> Result<()> {
+        self.provide_linear_memory(module, name, |page_size| {
+            LinearMemory::new(memory, page_size)
+        })
+    }
+
+    // Finds the import and checks the memory `memory` makes for it, given the import's page size
+    fn provide_linear_memory(
+        self: Pin<&mut Self>,
+        module: &str,
+        name: &str,
+        memory: impl FnOnce(u32) -> LinearMemory,
+    ) -> Result<()> {
+        // SAFETY: Only the imports are modified, the module itself is never moved
+        let this = unsafe { self.get_unchecked_mut() };
+        let index = this
+            .memory_imports
+            .iter()
+            .position(|import| import.module == module && import.name == name)
+            .ok_or_else(|| WasmError::MemoryNotFound(format!("{}.{}", module, name)))?;
+
+        let ty = this.memory_types[index];
+        let memory = memory(1 << ty.page_size_log2.unwrap_or(16));
+        check_memory_import(&ty, memory.bytes()).map_err(|reason| {
+            WasmError::InvalidMemoryImport {
+                module: module.to_string(),
+                name: name.to_string(),
+                reason,
+            }
+        })?;
+
+        this.memory_imports[index].memory = Some(memory);
+        Ok(())
+    }
+
+    // The type of the item with the given kind and index, if there is one
+    fn extern_type(&self, kind: ExternalKind, index: u32) -> Option<ExternType<'_>> {
+        let index = index as usize;
+        Some(match kind {
+            ExternalKind::Func => ExternType::Func(self.functions.get(index)?.ty()),
+            ExternalKind::Table => ExternType::Table(*self.table_types.get(index)?),
+            ExternalKind::Memory => ExternType::Memory(*self.memory_types.get(index)?),
+            ExternalKind::Global => ExternType::Global(*self.global_types.get(index)?),
+            ExternalKind::Tag => ExternType::Tag(self.types.get(*self.tags.get(index)? as usize)?),
+        })
+    }
+
+    /// Every import, in the order of the import section
+    ///
+    /// Like the rest of the module's description, this is available from the `provide_imports`
+    /// callback, before any memory is allocated or code runs.
+    pub fn imports(&self) -> impl Iterator<Item = Import<'_>> {
+        // Imports are numbered as they are parsed, so every one of them has a type
+        self.imports
+            .iter()
+            .filter_map(|(module, name, kind, index)| {
+                Some(Import {
+                    module,
+                    name,
+                    kind: *kind,
+                    ty: self.extern_type(*kind, *index)?,
+                })
+            })
+    }
+
+    /// Every export, in the order of the export section
+    pub fn exports(&self) -> impl Iterator<Item = Export<'_>> {
+        // Exports of missing items were rejected by `parse`
+        self.exports.iter().filter_map(|(name, kind, index)| {
+            Some(Export {
+                name,
+                kind: *kind,
+                index: *index,
+                ty: self.extern_type(*kind, *index)?,
+            })
+        })
+    }
+
+    /// The signature of every function, imported ones first
+    pub fn function_types(&self) -> impl Iterator<Item = &FuncType> {
+        self.functions.iter().map(WasmFunction::ty)
+    }
+
+    /// The limits of every memory, imported ones first
+    pub fn memory_types(&self) -> &[MemoryType] {
+        &self.memory_types
+    }
+
+    /// The type of every global, imported ones first
+    pub fn global_types(&self) -> &[GlobalType] {
+        &self.global_types
+    }
+
+    pub fn table_types(&self) -> &[TableType] {
+        &self.table_types
+    }
+
+    /// The contents of the first custom section with the given name
+    pub fn custom_section(&self, name: &str) -> Option<&'a [u8]> {
+        self.custom_sections
+            .iter()
+            .find_map(|(n, data)| (*n == name).then_some(*data))
+    }
+
+    /// The name and contents of every custom section, names may repeat
+    pub fn custom_sections(&self) -> impl Iterator<Item = (&'a str, &'a [u8])> + '_ {
+        self.custom_sections.iter().copied()
+    }
+
+    // Index of the export with the given kind and name
+    fn export_index(&self, kind: ExternalKind, name: &str) -> Option<u32> {
+        self.exports
+            .iter()
+            .find_map(|(n, k, index)| (*k == kind && n == name).then_some(*index))
+    }
+
+    fn exported_global(&self, name: &str) -> Result<u32> {
+        self.export_index(ExternalKind::Global, name)
+            .ok_or_else(|| WasmError::GlobalNotFound(name.to_string()))
+    }
+
+    /// Reads an exported global, f32 values are returned as their bits
+    pub fn get_global(&self, name: &str) -> Result<u32> {
+        Ok(self.memory.get_global(self.exported_global(name)?))
+    }
+
+    /// Writes an exported global, which must be mutable
+    pub fn set_global(self: Pin<&mut Self>, name: &str, value: u32) -> Result<()> {
+        let index = self.exported_global(name)?;
+        if !self.globals[index as usize].ty.mutable {
+            return Err(WasmError::ImmutableGlobal(index));
+        }
+
+        self.memory_mut().set_global(index, value);
         Ok(())
     }
 
-    pub fn add_external_function(&mut self, module: &str, name: &str, function: ExternalFn<'a>) {
-        let index = self
+    fn exported_memory_index(&self, name: &str) -> Result<u32> {
+        self.export_index(ExternalKind::Memory, name)
+            .ok_or_else(|| WasmError::MemoryNotFound(name.to_string()))
+    }
+
+    /// Looks up an exported memory by its export name
+    pub fn exported_memory(&self, name: &str) -> Result<&LinearMemory> {
+        let index = self.exported_memory_index(name)?;
+        // Only missing before instantiation, which allocates every memory
+        self.memory
+            .memory(index)
+            .ok_or_else(|| WasmError::MemoryNotFound(name.to_string()))
+    }
+
+    /// Looks up an exported memory by its export name, for writing
+    ///
+    /// Only the contents can be changed through this, the memory itself stays where compiled code
+    /// expects it.
+    pub fn exported_memory_mut(self: Pin<&mut Self>, name: &str) -> Result<LinearMemoryMut<'_>> {
+        let index = self.exported_memory_index(name)?;
+        self.memory_mut()
+            .memory_mut(index)
+            .ok_or_else(|| WasmError::MemoryNotFound(name.to_string()))
+    }
+
+    /// Provides an imported function
+    ///
+    /// The function finds its params on the stack, with the last one on top, and has to pop all of
+    /// them and then push its results in order, so the last result ends up on top.
+    pub fn add_external_function(
+        self: Pin<&mut Self>,
+        module: &str,
+        name: &str,
+        function: ExternalFn<'a>,
+    ) {
+        // SAFETY: Only the function table is modified, the module itself is never moved
+        let this = unsafe { self.get_unchecked_mut() };
+        let index = this
             .functions
             .iter()
             .enumerate()
@@ -338,34 +1614,52 @@ impl<'a> WasmModule<'a> {
             })
             .unwrap();
 
-        if let WasmFunction::External { function: f, .. } = &mut self.functions[index] {
+        if let WasmFunction::External { function: f, .. } = &mut this.functions[index] {
             f.replace(function);
         }
     }
 }
 
 pub trait Call<RetType> {
-    fn call(&mut self, name: &str, args: &[u32]) -> Result<RetType>;
+    fn call(self: Pin<&mut Self>, name: &str, args: &[u32]) -> Result<RetType>;
 }
 
 impl Call<()> for WasmModule<'_> {
-    fn call(&mut self, name: &str, args: &[u32]) -> Result<()> {
+    fn call(self: Pin<&mut Self>, name: &str, args: &[u32]) -> Result<()> {
         self.internal_call(name, args)
     }
 }
 
 impl Call<i32> for WasmModule<'_> {
-    fn call(&mut self, name: &str, args: &[u32]) -> Result<i32> {
-        self.internal_call(name, args)?;
+    fn call(mut self: Pin<&mut Self>, name: &str, args: &[u32]) -> Result<i32> {
+        self.as_mut().internal_call(name, args)?;
 
-        Ok(self.memory.pop_stack() as i32)
+        Ok(self.memory_mut().pop_stack() as i32)
     }
 }
 
 impl Call<f32> for WasmModule<'_> {
-    fn call(&mut self, name: &str, args: &[u32]) -> Result<f32> {
-        self.internal_call(name, args)?;
+    fn call(mut self: Pin<&mut Self>, name: &str, args: &[u32]) -> Result<f32> {
+        self.as_mut().internal_call(name, args)?;
+
+        Ok(f32::from_bits(self.memory_mut().pop_stack()))
+    }
+}
+
+/// Returns every result of the function in order, f32 values as their bits
+impl Call<Vec<u32>> for WasmModule<'_> {
+    fn call(mut self: Pin<&mut Self>, name: &str, args: &[u32]) -> Result<Vec<u32>> {
+        let index = self.exported_function(name)?;
+        let WasmFunction::Jit { ty, .. } = &self.functions[index as usize] else {
+            unreachable!("Only JIT functions are exported");
+        };
+
+        let results = ty.len_outputs();
+        self.as_mut().call_index(index, args)?;
 
-        Ok(f32::from_bits(self.memory.pop_stack()))
+        let mut memory = self.memory_mut();
+        let mut values: Vec<u32> = (0..results).map(|_| memory.as_mut().pop_stack()).collect();
+        values.reverse(); // The last result was on top
+        Ok(values)
     }
 }
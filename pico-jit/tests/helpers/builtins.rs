@@ -0,0 +1,52 @@
+// The compiler links against the Cortex-M runtime routines that generated code calls, which only
+// exist on the device. Generated code never runs on the host, so these only have to link.
+
+#[no_mangle]
+extern "C" fn __aeabi_idiv() {
+    unreachable!()
+}
+
+#[no_mangle]
+extern "C" fn __aeabi_uidiv() {
+    unreachable!()
+}
+
+#[no_mangle]
+extern "C" fn __aeabi_idivmod() {
+    unreachable!()
+}
+
+#[no_mangle]
+extern "C" fn __aeabi_uidivmod() {
+    unreachable!()
+}
+
+#[no_mangle]
+extern "C" fn __aeabi_fadd() {
+    unreachable!()
+}
+
+#[no_mangle]
+extern "C" fn __aeabi_fsub() {
+    unreachable!()
+}
+
+#[no_mangle]
+extern "C" fn __aeabi_fmul() {
+    unreachable!()
+}
+
+#[no_mangle]
+extern "C" fn __aeabi_fdiv() {
+    unreachable!()
+}
+
+#[no_mangle]
+extern "C" fn __aeabi_i2f() {
+    unreachable!()
+}
+
+#[no_mangle]
+extern "C" fn __aeabi_ui2f() {
+    unreachable!()
+}
@@ -0,0 +1,41 @@
+use pico_jit::compiler::CompilerOptions;
+use pico_jit::image::CodeImage;
+use pico_jit::wasm_module::{Result, Trap};
+
+/// Assembles a module written in the text format
+pub fn wasm(source: &str) -> Vec<u8> {
+    wat::parse_str(source).expect("Test modules should be valid text")
+}
+
+/// Compiles every function defined in the module, like `pico-aot` does
+pub fn compile(source: &str) -> Result<CodeImage> {
+    compile_with(source, CompilerOptions::default())
+}
+
+/// Compiles every function defined in the module with the given options
+pub fn compile_with(source: &str, options: CompilerOptions) -> Result<CodeImage> {
+    CodeImage::compile(&wasm(source), options)
+}
+
+/// Generated code of the function with the given index
+pub fn code(image: &CodeImage, index: u32) -> &[u16] {
+    &image
+        .functions
+        .iter()
+        .find(|function| function.index == index)
+        .expect("Function should be in the image")
+        .code
+}
+
+/// Whether the instructions in `sequence` appear in `code` one after the other
+pub fn contains(code: &[u16], sequence: &[u16]) -> bool {
+    code.windows(sequence.len())
+        .any(|window| window == sequence)
+}
+
+/// Whether `code` raises `trap` directly, i.e. `movs r1, #trap` followed by storing r1 into the
+/// context's unwind field
+pub fn raises(code: &[u16], trap: Trap) -> bool {
+    code.windows(2)
+        .any(|pair| pair[0] == 0x2100 | trap as u16 && pair[1] & 0xf83f == 0x6021)
+}
@@ -0,0 +1,603 @@
+mod helpers;
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use helpers::modules::*;
+    use pico_jit::cache::{CacheKey, CodeCache, InMemoryCodeCache};
+    use pico_jit::compiler::{CompilerOptions, COMPILER_VERSION};
+    use pico_jit::image::CodeImage;
+    use pico_jit::wasm_module::{Call, Exception, Trap, WasmError, WasmModule};
+
+    // add sp, #4
+    const DROP_ONE: u16 = 0xb001;
+
+    // mov r8, sp; mov sp, r10, switching to the native stack before calling a helper
+    const TO_NATIVE_STACK: [u16; 2] = [0x46e8, 0x46d5];
+
+    // lsls r1, r0, #1; movs r2, #0xff; lsls r2, r2, #24; cmp r1, r2, the NaN test of
+    // canonicalization
+    // adds r0, #1; str r0, [r4, #68], storing the index plus one of the function to tail call
+    const SET_TAIL_CALL: [u16; 2] = [0x3001, 0x6460];
+
+    const IS_NAN: [u16; 4] = [0x0041, 0x22ff, 0x0612, 0x4291];
+
+    #[test]
+    fn drop_discards_one_word() {
+        let image = compile(
+            r#"(module
+                (func
+                    i32.const 1
+                    i32.const 2
+                    drop
+                    drop))"#,
+        )
+        .unwrap();
+
+        let code = code(&image, 0);
+        assert!(contains(code, &[DROP_ONE, DROP_ONE]));
+        assert!(!code.contains(&0xb004)); // add sp, #16, which used to drop four values
+    }
+
+    const ADD: &str = r#"(module
+        (func $add (export "add") (param i32 i32) (result i32)
+            local.get 0
+            local.get 1
+            i32.add)
+        (func $double (param i32) (result i32)
+            local.get 0
+            local.get 0
+            call $add))"#;
+
+    #[test]
+    fn image_round_trips() {
+        let image = compile(ADD).unwrap();
+        let copy = CodeImage::from_bytes(&image.to_bytes()).unwrap();
+
+        assert_eq!(copy.compiler_version, COMPILER_VERSION);
+        assert_eq!(copy.compiler_options, image.compiler_options);
+        assert_eq!(copy.module_hash, image.module_hash);
+        assert_eq!(copy.functions.len(), 2);
+        for (copy, original) in copy.functions.iter().zip(&image.functions) {
+            assert_eq!(copy.index, original.index);
+            assert_eq!(copy.code, original.code);
+        }
+        assert_eq!(copy.exports.len(), 1);
+        assert_eq!(copy.exports[0].name, "add");
+        assert_eq!(copy.exports[0].index, 0);
+    }
+
+    #[test]
+    fn truncated_image_is_invalid() {
+        let bytes = compile(ADD).unwrap().to_bytes();
+        for len in [0, 3, bytes.len() / 2, bytes.len() - 1] {
+            assert!(matches!(
+                CodeImage::from_bytes(&bytes[..len]),
+                Err(WasmError::InvalidImage(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn oversized_length_in_image_is_invalid() {
+        // An image without functions or exports, which then claims an export with a huge name
+        let mut bytes = compile("(module)").unwrap().to_bytes();
+        let export_count = bytes.len() - 4;
+        bytes[export_count..].copy_from_slice(&1u32.to_le_bytes());
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+
+        assert!(matches!(
+            CodeImage::from_bytes(&bytes),
+            Err(WasmError::InvalidImage(_))
+        ));
+    }
+
+    #[test]
+    fn module_instantiates_from_image() {
+        let wasm = wasm(ADD);
+        let image = compile(ADD).unwrap();
+        let module =
+            WasmModule::from_wasm_with_image(&wasm, &image.to_bytes(), |_| Ok(())).unwrap();
+
+        // Every function was installed from the image, none is left to compile
+        let code_size: usize = image.functions.iter().map(|f| f.code.len() * 2).sum();
+        assert_eq!(module.code_size(), code_size);
+    }
+
+    #[test]
+    fn image_of_other_module_is_rejected() {
+        let other = wasm("(module (func (export \"add\")))");
+        let image = compile(ADD).unwrap().to_bytes();
+        assert!(matches!(
+            WasmModule::from_wasm_with_image(&other, &image, |_| Ok(())),
+            Err(WasmError::ImageMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn image_of_other_compiler_version_is_rejected() {
+        let wasm = wasm(ADD);
+        let mut image = compile(ADD).unwrap();
+        image.compiler_version += 1;
+        assert!(matches!(
+            WasmModule::from_wasm_with_image(&wasm, &image.to_bytes(), |_| Ok(())),
+            Err(WasmError::ImageMismatch(_))
+        ));
+    }
+
+    fn cache_key(function_index: u32) -> CacheKey {
+        CacheKey {
+            module_hash: 0x1234_5678_9abc_def0,
+            function_index,
+            compiler_version: COMPILER_VERSION,
+            compiler_options: 0,
+        }
+    }
+
+    #[test]
+    fn cache_returns_code_stored_under_the_same_key() {
+        let mut cache = InMemoryCodeCache::new();
+        cache.insert(cache_key(1), &[1, 2, 3]);
+
+        assert_eq!(cache.get(&cache_key(1)).as_deref(), Some(&[1, 2, 3][..]));
+        assert_eq!(cache.get(&cache_key(2)), None);
+    }
+
+    #[test]
+    fn cache_drops_code_from_other_compiler_options() {
+        let mut cache = InMemoryCodeCache::new();
+        cache.insert(cache_key(1), &[1, 2, 3]);
+
+        let key = CacheKey {
+            compiler_options: 1,
+            ..cache_key(1)
+        };
+        assert_eq!(cache.get(&key), None);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn cache_round_trips() {
+        let mut cache = InMemoryCodeCache::new();
+        cache.insert(cache_key(1), &[1, 2, 3]);
+        cache.insert(cache_key(4), &[0xbd00]);
+
+        let mut copy = InMemoryCodeCache::from_bytes(&cache.to_bytes()).unwrap();
+        assert_eq!(copy.len(), 2);
+        assert_eq!(copy.get(&cache_key(1)).as_deref(), Some(&[1, 2, 3][..]));
+        assert_eq!(copy.get(&cache_key(4)).as_deref(), Some(&[0xbd00][..]));
+    }
+
+    #[test]
+    fn truncated_cache_is_invalid() {
+        let mut cache = InMemoryCodeCache::new();
+        cache.insert(cache_key(1), &[1, 2, 3]);
+        let bytes = cache.to_bytes();
+
+        assert!(matches!(
+            InMemoryCodeCache::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(WasmError::InvalidImage(_))
+        ));
+    }
+
+    fn binary_op(op: &str) -> String {
+        format!(
+            "(module (func (param i32 i32) (result i32) local.get 0 local.get 1 {}))",
+            op
+        )
+    }
+
+    #[test]
+    fn division_traps_on_zero() {
+        for op in ["i32.div_s", "i32.div_u", "i32.rem_s", "i32.rem_u"] {
+            let image = compile(&binary_op(op)).unwrap();
+            assert!(raises(code(&image, 0), Trap::IntegerDivideByZero), "{}", op);
+        }
+    }
+
+    #[test]
+    fn only_signed_division_traps_on_overflow() {
+        let image = compile(&binary_op("i32.div_s")).unwrap();
+        assert!(raises(code(&image, 0), Trap::IntegerOverflow));
+
+        // INT_MIN % -1 is 0, and unsigned division can't overflow
+        for op in ["i32.div_u", "i32.rem_s", "i32.rem_u"] {
+            let image = compile(&binary_op(op)).unwrap();
+            assert!(!raises(code(&image, 0), Trap::IntegerOverflow), "{}", op);
+        }
+    }
+
+    fn float_op(op: &str, result: &str) -> String {
+        format!(
+            "(module (func (param f32 f32) (result {}) local.get 0 local.get 1 {}))",
+            result, op
+        )
+    }
+
+    #[test]
+    fn arithmetic_canonicalizes_nans_only_when_asked() {
+        let canonicalize = CompilerOptions {
+            canonicalize_nans: true,
+        };
+        for op in ["f32.min", "f32.max", "f32.add"] {
+            let source = float_op(op, "f32");
+            let image = compile_with(&source, canonicalize).unwrap();
+            assert!(contains(code(&image, 0), &IS_NAN), "{}", op);
+
+            let image = compile(&source).unwrap();
+            assert!(!contains(code(&image, 0), &IS_NAN), "{}", op);
+        }
+    }
+
+    #[test]
+    fn copysign_keeps_nan_payloads() {
+        let canonicalize = CompilerOptions {
+            canonicalize_nans: true,
+        };
+        let image = compile_with(&float_op("f32.copysign", "f32"), canonicalize).unwrap();
+        assert!(!contains(code(&image, 0), &IS_NAN));
+    }
+
+    #[test]
+    fn comparisons_test_the_ordering_bits() {
+        // The ordering is 0 if either side is NaN, so every comparison but ne is false then.
+        // movs r1, #mask; ands r0, r1
+        for (op, mask) in [
+            ("f32.eq", 2),
+            ("f32.lt", 1),
+            ("f32.gt", 4),
+            ("f32.le", 3),
+            ("f32.ge", 6),
+        ] {
+            let image = compile(&float_op(op, "i32")).unwrap();
+            assert!(
+                contains(code(&image, 0), &[0x2100 | mask, 0x4008]),
+                "{}",
+                op
+            );
+        }
+    }
+
+    #[test]
+    fn bulk_memory_pops_its_operands_and_checks_for_traps() {
+        // mov sp, r8; add sp, #12; cmp r0, #0, back from the native stack with the three operands
+        // dropped and the helper's trap code tested
+        const POP_AND_CHECK: [u16; 3] = [0x46c5, 0xb003, 0x2800];
+        for op in ["memory.copy", "memory.fill", "memory.init 0"] {
+            let image = compile(&format!(
+                r#"(module
+                    (memory 1)
+                    (data "bytes")
+                    (func (param i32 i32 i32)
+                        local.get 0
+                        local.get 1
+                        local.get 2
+                        {}))"#,
+                op
+            ))
+            .unwrap();
+            assert!(contains(code(&image, 0), &POP_AND_CHECK), "{}", op);
+        }
+    }
+
+    const HOST_START: &str = r#"(module
+        (import "env" "boot" (func $boot))
+        (start $boot))"#;
+
+    #[test]
+    fn start_function_runs_at_instantiation() {
+        let ran = core::cell::Cell::new(false);
+        let wasm = wasm(HOST_START);
+        WasmModule::from_wasm_with_imports(&wasm, |module| {
+            module.add_external_function("env", "boot", Box::new(|_| ran.set(true)));
+            Ok(())
+        })
+        .unwrap();
+
+        assert!(ran.get());
+    }
+
+    #[test]
+    fn start_function_failure_is_reported() {
+        let wasm = wasm(HOST_START);
+        match WasmModule::from_wasm(&wasm) {
+            Err(WasmError::StartFunctionFailed(error)) => assert!(matches!(
+                *error,
+                WasmError::ImportNotProvided { ref name, .. } if name == "boot"
+            )),
+            _ => panic!("Start function should have failed"),
+        };
+    }
+
+    #[test]
+    fn import_callback_error_stops_instantiation() {
+        let ran = core::cell::Cell::new(false);
+        let wasm = wasm(HOST_START);
+        let result = WasmModule::from_wasm_with_imports(&wasm, |module| {
+            module.add_external_function("env", "boot", Box::new(|_| ran.set(true)));
+            Err(WasmError::GlobalNotFound("missing".into()))
+        });
+
+        assert!(matches!(result, Err(WasmError::GlobalNotFound(_))));
+        assert!(!ran.get());
+    }
+
+    fn many_locals(count: usize, body: &str) -> String {
+        format!(
+            "(module (func (param i32) (local {}) {}))",
+            " i32".repeat(count),
+            body
+        )
+    }
+
+    #[test]
+    fn locals_past_immediate_offsets_are_reached() {
+        // ldr r0, [r7, r1], loading a local whose offset was put into r1
+        const LOAD_BY_OFFSET: u16 = 0x5878;
+
+        // 300 locals and the param, so local 250 is 50 words from the last one
+        let image = compile(&many_locals(300, "local.get 250 drop")).unwrap();
+        assert!(contains(code(&image, 0), &[0x2100 | 200, LOAD_BY_OFFSET])); // movs r1, #200
+
+        // Local 0 is 300 words away, which only fits in a literal, ldr r1, [pc, #x]
+        let image = compile(&many_locals(300, "local.get 0 drop")).unwrap();
+        let code = code(&image, 0);
+        assert!(code
+            .windows(2)
+            .any(|pair| pair[0] & 0xff00 == 0x4900 && pair[1] == LOAD_BY_OFFSET));
+    }
+
+    #[test]
+    fn frame_check_covers_locals_operands_and_helpers() {
+        let image = compile(&many_locals(
+            300,
+            "local.get 0 local.get 1 i32.add local.set 300",
+        ))
+        .unwrap();
+        let code = code(&image, 0);
+
+        // 300 zeroed locals, at most 2 operands and 64 words for helpers, as a literal
+        assert!(contains(code, &[(300 + 2 + 64) * 4, 0]));
+        assert!(raises(code, Trap::StackOverflow));
+    }
+
+    #[test]
+    fn too_many_locals_are_rejected() {
+        assert!(compile(&many_locals(49_999, "")).is_ok());
+        assert!(matches!(
+            compile(&many_locals(50_000, "")),
+            Err(WasmError::TooManyLocals(50_001))
+        ));
+    }
+
+    fn many_results(count: usize) -> String {
+        format!(
+            "(module (func (result {}) {}))",
+            " i32".repeat(count),
+            "i32.const 7 ".repeat(count)
+        )
+    }
+
+    #[test]
+    fn few_results_are_copied_one_by_one() {
+        // ldr r1, [sp, #8]; str r1, [r7, #8], the first of three results
+        let image = compile(&many_results(3)).unwrap();
+        assert!(contains(code(&image, 0), &[0x9902, 0x60b9]));
+    }
+
+    #[test]
+    fn many_results_are_copied_in_a_loop() {
+        // subs r2, #4; subs r3, #4; ldr r1, [r2]; str r1, [r3]; cmp r3, r7
+        let image = compile(&many_results(20)).unwrap();
+        assert!(contains(
+            code(&image, 0),
+            &[0x3a04, 0x3b04, 0x6811, 0x6019, 0x42bb]
+        ));
+    }
+
+    #[test]
+    fn blocks_take_and_give_several_values() {
+        compile(
+            r#"(module
+                (func $swap (param i32 i32) (result i32 i32)
+                    local.get 1
+                    local.get 0)
+                (func (param i32) (result i32 i32 i32)
+                    i32.const 1
+                    i32.const 2
+                    block (param i32 i32) (result i32 i32)
+                        call $swap
+                    end
+                    local.get 0
+                    if (param i32 i32) (result i32 i32 i32)
+                        i32.const 3
+                    else
+                        i32.const 4
+                        br 0
+                    end))"#,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn calling_an_unknown_export_fails() {
+        let wasm = wasm(&many_results(3));
+        let mut module = WasmModule::from_wasm(&wasm).unwrap();
+        let result: Result<Vec<u32>, _> = module.as_mut().call("missing", &[]);
+        assert!(matches!(result, Err(WasmError::FunctionNotFound(_))));
+    }
+
+    const TABLES: &str = r#"(module
+        (table $funcs 4 funcref)
+        (table $refs 2 8 externref)
+        (elem (table $funcs) (i32.const 1) func $first $second)
+        (elem $passive func $second)
+        (func $first)
+        (func $second))"#;
+
+    #[test]
+    fn active_element_segments_fill_tables() {
+        let wasm = wasm(TABLES);
+        let mut module = WasmModule::from_wasm(&wasm).unwrap();
+        let memory = module.as_mut().memory_mut();
+
+        // Functions are stored as their index plus one, so null is 0
+        let elements: Vec<u32> = (0..4)
+            .map(|index| memory.get_table_element(0, index).unwrap())
+            .collect();
+        assert_eq!(elements, [0, 1, 2, 0]);
+        assert_eq!(memory.get_table_size(1).unwrap(), 2);
+    }
+
+    #[test]
+    fn table_accessors_check_indices() {
+        let wasm = wasm(TABLES);
+        let mut module = WasmModule::from_wasm(&wasm).unwrap();
+        let mut memory = module.as_mut().memory_mut();
+
+        memory.as_mut().set_table_element(1, 1, 42).unwrap();
+        assert_eq!(memory.get_table_element(1, 1).unwrap(), 42);
+
+        assert!(matches!(
+            memory.get_table_element(1, 2),
+            Err(WasmError::TableOutOfBounds { table: 1, index: 2 })
+        ));
+        assert!(matches!(
+            memory.as_mut().set_table_element(2, 0, 42),
+            Err(WasmError::TableNotFound(2))
+        ));
+        assert!(matches!(
+            memory.get_table_size(2),
+            Err(WasmError::TableNotFound(2))
+        ));
+        assert!(memory.get_table_ptr(2).is_err());
+    }
+
+    #[test]
+    fn element_segment_outside_table_traps() {
+        let wasm = wasm(
+            r#"(module
+                (table 2 funcref)
+                (elem (i32.const 1) func $f $f)
+                (func $f))"#,
+        );
+        assert!(matches!(
+            WasmModule::from_wasm(&wasm),
+            Err(WasmError::Trap(Trap::OutOfBoundsTableAccess))
+        ));
+    }
+
+    #[test]
+    fn table_instructions_compile() {
+        compile(
+            r#"(module
+                (type $void (func))
+                (table $funcs 4 funcref)
+                (table $refs 2 8 externref)
+                (elem $passive func $f)
+                (func $f (param externref) (result i32)
+                    (table.set $refs (i32.const 0) (local.get 0))
+                    (drop (table.get $refs (i32.const 1)))
+                    (drop (table.grow $refs (ref.null extern) (i32.const 2)))
+                    (table.fill $refs (i32.const 0) (local.get 0) (i32.const 2))
+                    (table.copy $funcs $funcs (i32.const 0) (i32.const 1) (i32.const 2))
+                    (table.init $funcs $passive (i32.const 0) (i32.const 0) (i32.const 1))
+                    (elem.drop $passive)
+                    (call_indirect $funcs (type $void) (i32.const 0))
+                    (table.size $refs)))"#,
+        )
+        .unwrap();
+    }
+
+    const TAIL_CALLS: &str = r#"(module
+        (type $sum (func (param i32 i32 i32) (result i32)))
+        (table 1 funcref)
+        (elem (i32.const 0) $add3)
+        (func $add3 (type $sum)
+            local.get 0
+            local.get 1
+            i32.add
+            local.get 2
+            i32.add)
+        (func $direct (param i32) (result i32)
+            local.get 0
+            i32.const 1
+            i32.const 2
+            return_call $add3)
+        (func $indirect (param i32) (result i32)
+            local.get 0
+            local.get 0
+            local.get 0
+            i32.const 0
+            return_call_indirect (type $sum))
+        (func $plain (param i32) (result i32)
+            local.get 0
+            local.get 0
+            local.get 0
+            call $add3))"#;
+
+    #[test]
+    fn tail_calls_leave_the_call_to_the_caller() {
+        let image = compile(TAIL_CALLS).unwrap();
+        assert!(contains(code(&image, 1), &SET_TAIL_CALL));
+        assert!(contains(code(&image, 2), &SET_TAIL_CALL));
+        assert!(!contains(code(&image, 3), &SET_TAIL_CALL));
+    }
+
+    #[test]
+    fn tail_call_with_other_results_is_rejected() {
+        let result = compile(
+            r#"(module
+                (func $unit)
+                (func (result i32)
+                    return_call $unit))"#,
+        );
+        assert!(matches!(result, Err(WasmError::ParseError(_))));
+    }
+
+    const EXCEPTIONS: &str = r#"(module
+        (tag $pair (param i32 i32))
+        (func $raise (param i32)
+            local.get 0
+            i32.const 7
+            throw $pair)
+        (func (param i32) (result i32)
+            try (result i32)
+                local.get 0
+                call $raise
+                i32.const 0
+            catch $pair
+                i32.add
+            catch_all
+                rethrow 0
+            end))"#;
+
+    #[test]
+    fn exception_helpers_run_on_the_native_stack() {
+        let image = compile(EXCEPTIONS).unwrap();
+        assert!(contains(code(&image, 0), &TO_NATIVE_STACK)); // throw
+        assert!(contains(code(&image, 1), &TO_NATIVE_STACK)); // catch and rethrow
+    }
+
+    #[test]
+    fn throw_must_match_its_tag() {
+        let result = compile(
+            r#"(module
+                (tag $pair (param i32 i32))
+                (func
+                    i32.const 1
+                    throw $pair))"#,
+        );
+        assert!(matches!(result, Err(WasmError::ParseError(_))));
+    }
+
+    #[test]
+    fn uncaught_exception_names_tag_and_payload() {
+        // Compiled code can't run on the host, so this only covers what the host gets to see
+        let error = WasmError::UncaughtException(Exception {
+            tag: 1,
+            payload: vec![2, 3],
+        });
+        assert_eq!(error.to_string(), "Uncaught exception with tag 1: [2, 3]");
+    }
+}